@@ -1,55 +1,367 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{CandidType, Decode, Encode, Principal};
+#[cfg(not(test))]
+use ic_cdk::{api::is_controller, api::print, api::time, caller};
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
-use validator::Validate;
+use serde::de::DeserializeOwned;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::BTreeSet;
+use std::{borrow::Cow, cell::RefCell, cmp::Reverse, ops::Bound, time::Duration};
+#[cfg(test)]
+use tests::{caller, is_controller, print, time};
+use validator::{Validate, ValidationError};
 
 // Define type aliases for convenience
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
+// Current shape of stored products, bump whenever the Product struct changes
+const PRODUCT_SCHEMA_VERSION: u16 = 16;
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Product {
+    schema_version: u16,
     id: u64,
+    // human readable id such as "PRD-000123"
+    display_id: String,
     name: String,
     quantity: u32,
+    unit_of_measure: String,
     category: String,
-    warehouse: Warehouse,
+    // sale price of one unit
+    unit_price: u64,
+    // purchase cost of one unit
+    cost_price: u64,
+    image_url: Option<String>,
+    // an alert is raised when dispensing leaves quantity at or below this
+    alert_threshold: u32,
+    // most units that may be held, 0 means no cap
+    max_stock: u32,
+    // units held back that removals may not touch
+    safety_stock: u32,
+    // quantity at or below which the product should be reordered
+    reorder_level: u32,
+    // lot the units belong to, several lots of one item are separate products
+    lot_number: String,
+    // expiry time of the lot, 0 means it doesn't expire
+    expires_at: u64,
+    // cumulative quantity dispensed over the product's lifetime
+    total_dispensed: u64,
+    // shipping weight and space taken by a single unit
+    weight_grams: u32,
+    volume_cm3: u32,
+    // warehouse holding the product
+    warehouse_id: u64,
     added_at: u64,
     re_stocked_at: u64,
+    // last time the product was changed in any way
+    updated_at: u64,
+    // incremented on every change, used to detect conflicting edits
+    version: u64,
+    // set when an empty product has been tidied away, listings and searches leave it out
+    // until it is restocked
+    archived: bool,
+}
+
+// Schema version prefixed to every encoded record
+const STORAGE_VERSION: u8 = 1;
+
+// Encode a record as candid bytes prefixed with the storage schema version
+fn encode_versioned<T: CandidType>(value: &T) -> Cow<'static, [u8]> {
+    let mut bytes = vec![STORAGE_VERSION];
+    bytes.extend(Encode!(value).unwrap());
+    Cow::Owned(bytes)
+}
+
+// Candid bytes of a versioned record, None when the storage version is unknown. records
+// written before versioning start directly with the candid "DIDL" magic
+fn versioned_payload(bytes: &[u8]) -> Option<&[u8]> {
+    match bytes.first() {
+        Some(&STORAGE_VERSION) => Some(&bytes[1..]),
+        _ if bytes.starts_with(b"DIDL") => Some(bytes),
+        _ => None,
+    }
+}
+
+// Default stand-in for a stored record that can't be read, with a warning in the
+// canister log so the loss doesn't go unnoticed
+fn unreadable_record<T: Default>(kind: &str, reason: &str) -> T {
+    print(format!(
+        "warning: stored {} replaced by a default, {}",
+        kind, reason
+    ));
+    T::default()
+}
+
+// Decode a versioned record, falling back to a default when it can't be read
+fn decode_versioned<T: CandidType + DeserializeOwned + Default>(bytes: &[u8], kind: &str) -> T {
+    match versioned_payload(bytes) {
+        Some(payload) => Decode!(payload, T).unwrap_or_else(|err| {
+            unreadable_record(kind, &format!("could not decode it: {}", err))
+        }),
+        None => unreadable_record(kind, "unknown storage version"),
+    }
+}
+
+// Decode a versioned record in its current shape, or as the legacy shape L written by
+// earlier versions of the canister and converted to the current one. a record in neither
+// shape falls back to a default
+fn decode_versioned_or_legacy<T, L>(bytes: &[u8], kind: &str) -> T
+where
+    T: CandidType + DeserializeOwned + Default,
+    L: CandidType + DeserializeOwned + Into<T>,
+{
+    let payload = match versioned_payload(bytes) {
+        Some(payload) => payload,
+        None => return unreadable_record(kind, "unknown storage version"),
+    };
+    match Decode!(payload, T) {
+        Ok(value) => value,
+        Err(_) => match Decode!(payload, L) {
+            Ok(legacy) => legacy.into(),
+            Err(err) => unreadable_record(kind, &format!("could not decode it: {}", err)),
+        },
+    }
 }
 
 // Implement the 'Storable' traits
 
 impl Storable for Product {
     // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
     }
-    // Conversion from bytes
+    // Conversion from bytes, products stored by an older schema version are converted
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned_or_legacy::<Product, ProductRecord>(bytes.as_ref(), "product")
     }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+// Product as stored by any earlier schema version. fields were only ever added, so every
+// field added since the first version is optional and gets its default when missing
+#[derive(candid::CandidType, Deserialize)]
+struct ProductRecord {
+    schema_version: Option<u16>,
+    id: u64,
+    display_id: Option<String>,
+    name: String,
+    quantity: u32,
+    unit_of_measure: Option<String>,
+    category: String,
+    unit_price: Option<u64>,
+    cost_price: Option<u64>,
+    image_url: Option<String>,
+    alert_threshold: Option<u32>,
+    max_stock: Option<u32>,
+    safety_stock: Option<u32>,
+    reorder_level: Option<u32>,
+    lot_number: Option<String>,
+    expires_at: Option<u64>,
+    total_dispensed: Option<u64>,
+    weight_grams: Option<u32>,
+    volume_cm3: Option<u32>,
+    warehouse_id: Option<u64>,
+    // products stored a full copy of their warehouse before schema version 16
+    warehouse: Option<EmbeddedWarehouse>,
+    added_at: u64,
+    re_stocked_at: u64,
+    updated_at: Option<u64>,
+    version: Option<u64>,
+    archived: Option<bool>,
+}
+
+// The part of an embedded warehouse copy that is still needed
+#[derive(candid::CandidType, Deserialize)]
+struct EmbeddedWarehouse {
+    id: u64,
+}
+
+impl From<ProductRecord> for Product {
+    fn from(record: ProductRecord) -> Self {
+        Product {
+            schema_version: record.schema_version.unwrap_or(0),
+            id: record.id,
+            display_id: record.display_id.unwrap_or_default(),
+            name: record.name,
+            quantity: record.quantity,
+            // products from before unit_of_measure existed were counted in single items
+            unit_of_measure: record.unit_of_measure.unwrap_or_else(|| "each".to_string()),
+            category: record.category,
+            unit_price: record.unit_price.unwrap_or(0),
+            cost_price: record.cost_price.unwrap_or(0),
+            image_url: record.image_url,
+            alert_threshold: record.alert_threshold.unwrap_or(0),
+            max_stock: record.max_stock.unwrap_or(0),
+            safety_stock: record.safety_stock.unwrap_or(0),
+            reorder_level: record.reorder_level.unwrap_or(0),
+            lot_number: record.lot_number.unwrap_or_default(),
+            expires_at: record.expires_at.unwrap_or(0),
+            total_dispensed: record.total_dispensed.unwrap_or(0),
+            weight_grams: record.weight_grams.unwrap_or(0),
+            volume_cm3: record.volume_cm3.unwrap_or(0),
+            warehouse_id: record
+                .warehouse_id
+                .or(record.warehouse.map(|warehouse| warehouse.id))
+                .unwrap_or(0),
+            added_at: record.added_at,
+            re_stocked_at: record.re_stocked_at,
+            updated_at: record.updated_at.unwrap_or(0),
+            version: record.version.unwrap_or(0),
+            archived: record.archived.unwrap_or(false),
+        }
+    }
+}
+
+// Current shape of stored warehouses, bump whenever the Warehouse struct changes
+const WAREHOUSE_SCHEMA_VERSION: u16 = 1;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct Warehouse {
+    schema_version: u16,
     id: u64,
+    // human readable id such as "WHS-000007"
+    display_id: String,
     name: String,
     address: String,
+    city: String,
+    owner: Principal,
+    // most units the warehouse can hold across all products, 0 means unlimited
+    capacity: u32,
+    // whether capacity is counted in units or in volume
+    capacity_mode: CapacityMode,
+    // most volume the warehouse can hold when counting by volume, 0 means unlimited
+    volume_capacity_cm3: u64,
+    // inactive warehouses don't take in new products
+    is_active: bool,
+    // categories the warehouse accepts, empty means any
+    allowed_categories: Vec<String>,
+    // fewest units a newly added product must arrive with, 0 means no minimum
+    min_initial_stock: u32,
+    // most distinct categories held at once, 0 means unlimited
+    max_distinct_categories: u32,
+    // who to reach about deliveries and pickups
+    contact_email: String,
+    contact_phone: String,
+    // (weekday 0-6, open minute, close minute) windows, empty means always open
+    opening_hours: Vec<(u8, u32, u32)>,
+    // (latitude, longitude) in degrees
+    coordinates: Option<(f64, f64)>,
+    // lowercase grouping labels such as "cold" or "region-east"
+    tags: Vec<String>,
+    // most units dispensed from the warehouse per day, 0 means unlimited
+    daily_dispense_limit: u32,
+    created_at: u64,
+    // last time the warehouse was changed in any way
+    updated_at: u64,
+}
+
+// How the capacity of a warehouse is measured
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum CapacityMode {
+    #[default]
+    Units,
+    Volume,
+}
+
+impl Default for Warehouse {
+    fn default() -> Self {
+        Warehouse {
+            schema_version: 0,
+            id: 0,
+            display_id: String::new(),
+            name: String::new(),
+            address: String::new(),
+            city: String::new(),
+            owner: Principal::anonymous(),
+            capacity: 0,
+            capacity_mode: CapacityMode::Units,
+            volume_capacity_cm3: 0,
+            is_active: false,
+            allowed_categories: Vec::new(),
+            min_initial_stock: 0,
+            max_distinct_categories: 0,
+            contact_email: String::new(),
+            contact_phone: String::new(),
+            opening_hours: Vec::new(),
+            coordinates: None,
+            tags: Vec::new(),
+            daily_dispense_limit: 0,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
 }
 
 impl Storable for Warehouse {
     // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
     }
-    // Conversion from bytes
+    // Conversion from bytes, warehouses stored in an older shape are converted
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned_or_legacy::<Warehouse, WarehouseRecord>(bytes.as_ref(), "warehouse")
+    }
+}
+
+// Warehouse as stored by earlier versions of the canister, with every field added
+// since the first version optional
+#[derive(candid::CandidType, Deserialize)]
+struct WarehouseRecord {
+    schema_version: Option<u16>,
+    id: u64,
+    display_id: Option<String>,
+    name: String,
+    address: String,
+    city: Option<String>,
+    owner: Option<Principal>,
+    capacity: Option<u32>,
+    capacity_mode: Option<CapacityMode>,
+    volume_capacity_cm3: Option<u64>,
+    is_active: Option<bool>,
+    allowed_categories: Option<Vec<String>>,
+    min_initial_stock: Option<u32>,
+    max_distinct_categories: Option<u32>,
+    contact_email: Option<String>,
+    contact_phone: Option<String>,
+    opening_hours: Option<Vec<(u8, u32, u32)>>,
+    coordinates: Option<(f64, f64)>,
+    tags: Option<Vec<String>>,
+    daily_dispense_limit: Option<u32>,
+    created_at: Option<u64>,
+    updated_at: Option<u64>,
+}
+
+impl From<WarehouseRecord> for Warehouse {
+    fn from(record: WarehouseRecord) -> Self {
+        Warehouse {
+            schema_version: record.schema_version.unwrap_or(0),
+            id: record.id,
+            display_id: record.display_id.unwrap_or_default(),
+            name: record.name,
+            address: record.address,
+            city: record.city.unwrap_or_default(),
+            // warehouses from before ownership was tracked are left to the controllers
+            owner: record.owner.unwrap_or_else(Principal::anonymous),
+            capacity: record.capacity.unwrap_or(0),
+            capacity_mode: record.capacity_mode.unwrap_or_default(),
+            volume_capacity_cm3: record.volume_capacity_cm3.unwrap_or(0),
+            // warehouses could always take in products before they could be deactivated
+            is_active: record.is_active.unwrap_or(true),
+            allowed_categories: record.allowed_categories.unwrap_or_default(),
+            min_initial_stock: record.min_initial_stock.unwrap_or(0),
+            max_distinct_categories: record.max_distinct_categories.unwrap_or(0),
+            contact_email: record.contact_email.unwrap_or_default(),
+            contact_phone: record.contact_phone.unwrap_or_default(),
+            opening_hours: record.opening_hours.unwrap_or_default(),
+            coordinates: record.coordinates,
+            tags: record.tags.unwrap_or_default(),
+            daily_dispense_limit: record.daily_dispense_limit.unwrap_or(0),
+            created_at: record.created_at.unwrap_or(0),
+            updated_at: record.updated_at.unwrap_or(0),
+        }
     }
 }
 
@@ -64,354 +376,5979 @@ impl BoundedStorable for Warehouse {
     const IS_FIXED_SIZE: bool = false;
 }
 
-// Define thread-local static variables for memory management and storage
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// Record of a single change to a product's unit price
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PriceChange {
+    product_id: u64,
+    old_price: u64,
+    new_price: u64,
+    changed_at: u64,
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+impl Storable for PriceChange {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(bytes.as_ref(), "price change")
+    }
+}
 
-    static PRODUCT_STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+impl BoundedStorable for PriceChange {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    static WAREHOUSE_STORAGE: RefCell<StableBTreeMap<u64, Warehouse, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-    ));
+// Record of a warehouse changing hands
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OwnershipChange {
+    warehouse_id: u64,
+    previous_owner: Principal,
+    new_owner: Principal,
+    changed_by: Principal,
+    changed_at: u64,
 }
 
-// Struct for payload date used in update functions
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
-struct WarehousePayload {
-    #[validate(length(min = 3))]
-    name: String,
-    #[validate(length(min = 3))]
-    address: String,
-    password: String,
-    city: String,
+impl Default for OwnershipChange {
+    fn default() -> Self {
+        OwnershipChange {
+            warehouse_id: 0,
+            previous_owner: Principal::anonymous(),
+            new_owner: Principal::anonymous(),
+            changed_by: Principal::anonymous(),
+            changed_at: 0,
+        }
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
-struct ProductPayload {
-    #[validate(length(min = 3))]
-    name: String,
-    category: String,
-    quantity: u32,
-    warehouse_id: u64,
+impl Storable for OwnershipChange {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(bytes.as_ref(), "ownership change")
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct EditProductPayload {
-    name: String,
-    product_id: u64,
+impl BoundedStorable for OwnershipChange {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct GetProductPayload {
-    product_id: u64,
-    amount: u32,
+// Most bytes a supplier delivery id may take
+const MAX_DELIVERY_ID_BYTES: usize = 64;
+
+// Supplier delivery id used as the key of the delivery log
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct DeliveryId(String);
+
+impl Storable for DeliveryId {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        DeliveryId(String::from_utf8_lossy(&bytes).into_owned())
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct EditWarehousePayload {
-    warehouse_id: u64,
-    name: String,
+impl BoundedStorable for DeliveryId {
+    const MAX_SIZE: u32 = MAX_DELIVERY_ID_BYTES as u32;
+    const IS_FIXED_SIZE: bool = false;
 }
 
+// Record of a processed supplier delivery, kept so a retried delivery isn't applied twice
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct AccessPayload {
-    doctor_id: u64,
-    product_id: u64,
-    doctor_password: String,
+struct Delivery {
+    delivery_id: String,
+    // (product id, amount) lines as they were restocked
+    items: Vec<(u64, u32)>,
+    processed_at: u64,
 }
 
-// Update function to add a product
-#[ic_cdk::update]
-fn add_product(payload: ProductPayload) -> Result<Product, Error> {
-    // validate payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
+impl Storable for Delivery {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(bytes.as_ref(), "delivery")
     }
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+impl BoundedStorable for Delivery {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    // get warehouse
-    let warehouse =
-        WAREHOUSE_STORAGE.with(|warehouses| warehouses.borrow().get(&payload.warehouse_id));
-    match warehouse {
-        Some(warehouse) => {
-            let product = Product {
-                id,
-                name: payload.name.clone(),
-                quantity: payload.quantity,
-                category: payload.category,
-                warehouse: warehouse.clone(),
-                added_at: time(),
-                re_stocked_at: time(),
-            };
+// Lowercased category used as the key of the category reorder defaults
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct CategoryKey(String);
 
-            match PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(id, product.clone())) {
-                None => Ok(product),
-                Some(_) => Err(Error::InvalidPayload {
-                    msg: format!("Could not add product name: {}", payload.name),
-                }),
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: format!("Warehouse of id: {} not found", payload.warehouse_id),
-        }),
+impl CategoryKey {
+    fn new(category: &str) -> Self {
+        CategoryKey(category.to_ascii_lowercase())
     }
 }
 
-// Define query function to get a product by ID
-#[ic_cdk::query]
-fn get_product_by_id(id: u64) -> Result<Product, Error> {
-    match PRODUCT_STORAGE.with(|products| products.borrow().get(&id)) {
-        Some(product) => Ok(product),
-        None => Err(Error::NotFound {
-            msg: format!("product id:{} does not exist", id),
-        }),
+impl Storable for CategoryKey {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        CategoryKey(String::from_utf8_lossy(&bytes).into_owned())
     }
 }
 
-// get all products
-#[ic_cdk::query]
-fn get_all_products() -> Result<Vec<Product>, Error> {
-    // Retrieve all products from the storage
-    let product_map: Vec<(u64, Product)> = PRODUCT_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the products from the tuple and create a vector
-    let products: Vec<Product> = product_map
-        .into_iter()
-        .map(|(_, product)| product)
-        .collect();
+impl BoundedStorable for CategoryKey {
+    const MAX_SIZE: u32 = MAX_LABEL_BYTES as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    match products.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no products found"),
-        }),
-        _ => Ok(products),
+// Reorder level given to new products of a category that don't set their own
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CategoryReorderDefault {
+    category: String,
+    reorder_level: u32,
+}
+
+impl Storable for CategoryReorderDefault {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(bytes.as_ref(), "category reorder default")
     }
 }
 
-// function to remove a given quantity fo product from a warehouse while cheking if product is available and if warehouse has enough quantity
-#[ic_cdk::update]
-fn remove_product_from_warehouse(payload: GetProductPayload) -> Result<Product, Error> {
-    let product = PRODUCT_STORAGE.with(|products| products.borrow().get(&payload.product_id));
-    match product {
-        Some(product) => {
-            if product.quantity < payload.amount {
-                return Err(Error::InvalidPayload {
-                    msg: format!("Not enough quantity of product: {}", product.name),
-                });
-            }
+impl BoundedStorable for CategoryReorderDefault {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-            let new_product = Product {
-                quantity: product.quantity - payload.amount,
-                ..product.clone()
-            };
+// Ledger entry for a change in a product's quantity, negative deltas are outbound
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct StockMovement {
+    id: u64,
+    product_id: u64,
+    delta: i64,
+    reason: MovementReason,
+    // free-text detail such as a reconcile note
+    note: Option<String>,
+    timestamp: u64,
+    // set once a later entry has undone this movement
+    reversed: bool,
+    // id of the movement this entry undoes
+    reverses: Option<u64>,
+}
 
-            match PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product.id, new_product.clone())) {
-                Some(_) => Ok(new_product),
-                None => Err(Error::InvalidPayload {
-                    msg: format!("Could not remove product name: {}", product.name),
-                }),
+// Why a product's quantity changed
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum MovementReason {
+    #[default]
+    Add,
+    Dispense,
+    Restock,
+    TransferIn,
+    TransferOut,
+    Reconcile,
+    Reverse,
+    Expire,
+    Archive,
+    Delete,
+}
+
+// Stock movement as recorded before reasons were codes, with the reason as free text
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyStockMovement {
+    id: u64,
+    product_id: u64,
+    delta: i64,
+    reason: String,
+    timestamp: u64,
+    // missing from movements recorded before they could be undone
+    reversed: Option<bool>,
+    reverses: Option<u64>,
+}
+
+impl From<LegacyStockMovement> for StockMovement {
+    fn from(legacy: LegacyStockMovement) -> Self {
+        let (reason, note) = match legacy.reason.as_str() {
+            "add" => (MovementReason::Add, None),
+            "dispense" => (MovementReason::Dispense, None),
+            "order" => (MovementReason::Dispense, Some("order".to_string())),
+            "restock" => (MovementReason::Restock, None),
+            "transfer in" => (MovementReason::TransferIn, None),
+            "transfer out" => (MovementReason::TransferOut, None),
+            "rebalance" if legacy.delta < 0 => {
+                (MovementReason::TransferOut, Some("rebalance".to_string()))
             }
+            "rebalance" => (MovementReason::TransferIn, Some("rebalance".to_string())),
+            "reverse" => (MovementReason::Reverse, None),
+            "archive" => (MovementReason::Archive, None),
+            "delete" => (MovementReason::Delete, None),
+            other => (
+                MovementReason::Reconcile,
+                Some(
+                    other
+                        .strip_prefix("reconcile: ")
+                        .unwrap_or(other)
+                        .to_string(),
+                ),
+            ),
+        };
+        StockMovement {
+            id: legacy.id,
+            product_id: legacy.product_id,
+            delta: legacy.delta,
+            reason,
+            note,
+            timestamp: legacy.timestamp,
+            reversed: legacy.reversed.unwrap_or(false),
+            reverses: legacy.reverses,
         }
-        None => Err(Error::NotFound {
-            msg: format!("product of id: {} not found", payload.product_id),
-        }),
     }
 }
 
-// function to add a given quantity fo product to a warehouse
-#[ic_cdk::update]
-fn add_product_to_warehouse(payload: GetProductPayload) -> Result<Product, Error> {
-    let product = PRODUCT_STORAGE.with(|products| products.borrow().get(&payload.product_id));
-    match product {
-        Some(product) => {
-            let new_product = Product {
-                quantity: product.quantity + payload.amount,
-                ..product.clone()
-            };
+impl Storable for StockMovement {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes, movements stored with a text reason are converted to a code
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned_or_legacy::<StockMovement, LegacyStockMovement>(
+            bytes.as_ref(),
+            "stock movement",
+        )
+    }
+}
 
-            match PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product.id, new_product.clone())) {
-                Some(_) => Ok(new_product),
-                None => Err(Error::InvalidPayload {
-                    msg: format!("Could not add product name: {}", product.name),
-                }),
-            }
+impl BoundedStorable for StockMovement {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Canister wide settings
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Config {
+    // record every get_product_logged call in the access log
+    log_reads: bool,
+    // periodically sample the cycles balance into the cycle usage log
+    track_cycles: bool,
+    // prefixes of the display ids given to new products and warehouses
+    product_id_prefix: String,
+    warehouse_id_prefix: String,
+    // currency every unit_price is denominated in
+    currency: CurrencyConfig,
+    // fewest characters a product or warehouse name may have
+    min_name_length: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_reads: false,
+            track_cycles: false,
+            product_id_prefix: "PRD".to_string(),
+            warehouse_id_prefix: "WHS".to_string(),
+            currency: CurrencyConfig {
+                code: "KES".to_string(),
+                minor_units: 2,
+            },
+            min_name_length: 3,
         }
-        None => Err(Error::NotFound {
-            msg: format!("product of id: {} not found", payload.product_id),
-        }),
     }
 }
 
-// update function to edit a product where authorizations is by password
-#[ic_cdk::update]
-fn edit_product(payload: EditProductPayload) -> Result<Product, Error> {
-    let product = PRODUCT_STORAGE.with(|products| products.borrow().get(&payload.product_id));
+// Currency prices are stored in, as an ISO 4217 code and its number of minor units,
+// e.g. "KES" with 2 means a unit_price of 150 is KES 1.50
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CurrencyConfig {
+    code: String,
+    minor_units: u8,
+}
 
-    match product {
-        Some(product) => {
-            let new_product = Product {
-                name: payload.name,
-                ..product.clone()
-            };
+impl Storable for Config {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes, configs stored before a setting existed get its default
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned_or_legacy::<Config, ConfigRecord>(bytes.as_ref(), "config")
+    }
+}
 
-            match PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product.id, new_product.clone())) {
-                Some(_) => Ok(new_product),
-                None => Err(Error::InvalidPayload {
-                    msg: format!("Could not edit product name: {}", product.name),
-                }),
-            }
+// Config as stored by earlier versions of the canister
+#[derive(candid::CandidType, Deserialize)]
+struct ConfigRecord {
+    log_reads: bool,
+    track_cycles: Option<bool>,
+    product_id_prefix: Option<String>,
+    warehouse_id_prefix: Option<String>,
+    currency: Option<CurrencyConfig>,
+    min_name_length: Option<u8>,
+}
+
+impl From<ConfigRecord> for Config {
+    fn from(record: ConfigRecord) -> Self {
+        let defaults = Config::default();
+        Config {
+            log_reads: record.log_reads,
+            track_cycles: record.track_cycles.unwrap_or(defaults.track_cycles),
+            product_id_prefix: record
+                .product_id_prefix
+                .unwrap_or(defaults.product_id_prefix),
+            warehouse_id_prefix: record
+                .warehouse_id_prefix
+                .unwrap_or(defaults.warehouse_id_prefix),
+            currency: record.currency.unwrap_or(defaults.currency),
+            min_name_length: record.min_name_length.unwrap_or(defaults.min_name_length),
         }
-        None => Err(Error::NotFound {
-            msg: format!("product of id: {} not found", payload.product_id),
-        }),
     }
 }
 
-// Query function to get all warehouses
-#[ic_cdk::query]
-fn get_all_warehouses() -> Result<Vec<Warehouse>, Error> {
-    // Retrieve all Warehouses from the storage
-    let warehousemap: Vec<(u64, Warehouse)> =
-        WAREHOUSE_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the Warehouses from the tuple and create a vector
-    let warehouses: Vec<Warehouse> = warehousemap
-        .into_iter()
-        .map(|(_, warehouse)| warehouse)
-        .collect();
+// Record of a caller reading a product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AccessLogEntry {
+    product_id: u64,
+    caller: Principal,
+    timestamp: u64,
+}
 
-    match warehouses.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no Warehouses found"),
-        }),
-        _ => Ok(warehouses),
+impl Default for AccessLogEntry {
+    fn default() -> Self {
+        AccessLogEntry {
+            product_id: 0,
+            caller: Principal::anonymous(),
+            timestamp: 0,
+        }
     }
 }
 
-// Get Warehouses by city and name content
-#[ic_cdk::query]
-fn get_warehouse_by_name(search: String) -> Result<Vec<Warehouse>, Error> {
-    let query = search.to_lowercase();
-    // Retrieve all Warehouses from the storage
-    let warehouse_map: Vec<(u64, Warehouse)> =
-        WAREHOUSE_STORAGE.with(|s| s.borrow().iter().collect());
-    let warehouses: Vec<Warehouse> = warehouse_map
-        .into_iter()
-        .map(|(_, warehouse)| warehouse)
-        .collect();
+impl Storable for AccessLogEntry {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(bytes.as_ref(), "access log entry")
+    }
+}
 
-    // Filter the warehouses by name
-    let incomplete_products: Vec<Warehouse> = warehouses
-        .into_iter()
-        .filter(|warehouse| (warehouse.name).to_lowercase().contains(&query))
-        .collect();
+impl BoundedStorable for AccessLogEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    // Check if any warehouses are found
-    match incomplete_products.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("No warehouses for name: {} could be found", query),
-        }),
-        _ => Ok(incomplete_products),
+// Low stock alert raised when a product is dispensed down to its alert threshold
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Alert {
+    id: u64,
+    product_id: u64,
+    quantity: u32,
+    threshold: u32,
+    timestamp: u64,
+    acknowledged: bool,
+}
+
+impl Storable for Alert {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(bytes.as_ref(), "alert")
     }
 }
 
-// get warehouse by ID
-#[ic_cdk::query]
-fn get_warehouse_by_id(id: u64) -> Result<Warehouse, Error> {
-    match WAREHOUSE_STORAGE.with(|warehouses| warehouses.borrow().get(&id)) {
-        Some(warehouse) => Ok(warehouse),
-        None => Err(Error::NotFound {
-            msg: format!("warehouse of id: {} not found", id),
-        }),
+impl BoundedStorable for Alert {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Sample of the cycles balance taken by the cycle tracking timer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CycleSample {
+    timestamp: u64,
+    balance: u128,
+    // cycles spent since the previous sample, 0 for the first one or after a top up
+    consumed: u128,
+}
+
+impl Storable for CycleSample {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(bytes.as_ref(), "cycle sample")
     }
 }
 
-// Create new Warehouse
-#[ic_cdk::update]
-fn add_warehouse(payload: WarehousePayload) -> Result<Warehouse, Error> {
-    // validate payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
+impl BoundedStorable for CycleSample {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Number of most recent reads kept in the access log
+const ACCESS_LOG_CAPACITY: u64 = 1000;
+
+// Number of most recent cycle samples kept, a week of hourly samples
+const CYCLE_USAGE_CAPACITY: u64 = 168;
+
+// Time between two cycle samples while cycle tracking is on
+const CYCLE_SAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Define thread-local static variables for memory management and storage
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static PRODUCT_STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    static WAREHOUSE_STORAGE: RefCell<StableBTreeMap<u64, Warehouse, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static PRICE_HISTORY: RefCell<StableBTreeMap<u64, PriceChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    static MOVEMENT_STORAGE: RefCell<StableBTreeMap<u64, StockMovement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static CONFIG: RefCell<Cell<Config, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), Config::default())
+            .expect("Cannot create the config")
+    );
+
+    static ACCESS_LOG: RefCell<StableBTreeMap<u64, AccessLogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static ALERT_STORAGE: RefCell<StableBTreeMap<u64, Alert, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static CYCLE_USAGE: RefCell<StableBTreeMap<u64, CycleSample, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static OWNERSHIP_HISTORY: RefCell<StableBTreeMap<u64, OwnershipChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static DELIVERY_LOG: RefCell<StableBTreeMap<DeliveryId, Delivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static CATEGORY_REORDER_DEFAULTS: RefCell<StableBTreeMap<CategoryKey, CategoryReorderDefault, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // units dispensed per (warehouse id, day since the epoch)
+    static DAILY_DISPENSED: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    // timers don't survive upgrades, so the running one is only kept on the heap
+    static CYCLE_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+// Append a price change to the price history log
+fn record_price_change(product_id: u64, old_price: u64, new_price: u64) {
+    PRICE_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let key = history.last_key_value().map_or(0, |(key, _)| key + 1);
+        history.insert(
+            key,
+            PriceChange {
+                product_id,
+                old_price,
+                new_price,
+                changed_at: time(),
+            },
+        );
+    });
+}
+
+// Allocate the next id shared by products and warehouses, the counter is read,
+// incremented and stored within a single borrow
+fn next_id() -> Result<u64, Error> {
+    ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let id = *counter.get();
+        let next = id.checked_add(1).ok_or(Error::InvalidPayload {
+            msg: "no ids left to allocate".to_string(),
+        })?;
+        counter.set(next).map_err(|_| Error::InvalidPayload {
+            msg: "could not store the id counter".to_string(),
+        })?;
+        Ok(id)
+    })
+}
+
+// Reject removing an amount of a product that would dip into its safety stock,
+// unless a controller overrides the reservation
+fn check_safety_stock(
+    product: &Product,
+    amount: u32,
+    override_safety_stock: bool,
+) -> Result<(), Error> {
+    if override_safety_stock {
+        return require_controller();
+    }
+    if product.quantity.saturating_sub(amount) < product.safety_stock {
         return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
+            msg: format!(
+                "removing {} of product: {} would go below its safety stock, {} units are reserved",
+                amount, product.name, product.safety_stock
+            ),
         });
     }
+    Ok(())
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+// Default reorder level of a category, 0 when none is set
+fn category_reorder_default(category: &str) -> u32 {
+    CATEGORY_REORDER_DEFAULTS.with(|defaults| {
+        defaults
+            .borrow()
+            .get(&CategoryKey::new(category))
+            .map_or(0, |default| default.reorder_level)
+    })
+}
 
-    let warehouse = Warehouse {
-        id,
-        name: payload.name.clone(),
-        address: payload.address,
+// Append a quantity change of a product to the movement ledger
+fn record_movement(product_id: u64, delta: i64, reason: MovementReason, note: Option<String>) {
+    MOVEMENT_STORAGE.with(|movements| {
+        let mut movements = movements.borrow_mut();
+        let id = movements.last_key_value().map_or(0, |(id, _)| id + 1);
+        movements.insert(
+            id,
+            StockMovement {
+                id,
+                product_id,
+                delta,
+                reason,
+                note,
+                timestamp: time(),
+                reversed: false,
+                reverses: None,
+            },
+        );
+    });
+}
+
+// Day since the epoch that a timestamp falls on
+fn day_of(timestamp_ns: u64) -> u64 {
+    const NS_PER_DAY: u64 = 86_400_000_000_000;
+    timestamp_ns / NS_PER_DAY
+}
+
+// Reject dispensing more from a warehouse today than its daily dispense limit allows
+fn check_daily_dispense_limit(warehouse_id: u64, amount: u32) -> Result<(), Error> {
+    let warehouse = match WAREHOUSE_STORAGE.with(|s| s.borrow().get(&warehouse_id)) {
+        Some(warehouse) if warehouse.daily_dispense_limit > 0 => warehouse,
+        _ => return Ok(()),
     };
+    let dispensed = DAILY_DISPENSED
+        .with(|d| d.borrow().get(&(warehouse_id, day_of(time()))))
+        .unwrap_or(0);
+    let left = (warehouse.daily_dispense_limit as u64).saturating_sub(dispensed);
+    if amount as u64 > left {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "warehouse: {} can only dispense {} more units today",
+                warehouse.name, left
+            ),
+        });
+    }
+    Ok(())
+}
 
-    match WAREHOUSE_STORAGE.with(|s| s.borrow_mut().insert(id, warehouse.clone())) {
-        Some(_) => Err(Error::InvalidPayload {
-            msg: format!("Could not add warehouse name: {}", payload.name),
-        }),
-        None => Ok(warehouse),
+// Add dispensed units to a warehouse's count for today, dropping the counts of earlier days
+fn record_daily_dispense(warehouse_id: u64, amount: u32) {
+    let today = day_of(time());
+    DAILY_DISPENSED.with(|d| {
+        let mut dispensed = d.borrow_mut();
+        let earlier: Vec<(u64, u64)> = dispensed
+            .range((warehouse_id, 0)..(warehouse_id, today))
+            .map(|(key, _)| key)
+            .collect();
+        for key in earlier {
+            dispensed.remove(&key);
+        }
+        let total = dispensed.get(&(warehouse_id, today)).unwrap_or(0) + amount as u64;
+        dispensed.insert((warehouse_id, today), total);
+    });
+}
+
+// Give back units counted against a warehouse on the day they were dispensed,
+// counts of earlier days are already dropped so those are left alone
+fn release_daily_dispense(warehouse_id: u64, amount: u64, dispensed_at: u64) {
+    let key = (warehouse_id, day_of(dispensed_at));
+    DAILY_DISPENSED.with(|d| {
+        let mut dispensed = d.borrow_mut();
+        if let Some(total) = dispensed.get(&key) {
+            dispensed.insert(key, total.saturating_sub(amount));
+        }
+    });
+}
+
+// Raise an alert when a product's quantity is at or below its alert threshold
+fn check_stock_alert(product: &Product) {
+    if product.quantity > product.alert_threshold {
+        return;
+    }
+    ALERT_STORAGE.with(|alerts| {
+        let mut alerts = alerts.borrow_mut();
+        let id = alerts.last_key_value().map_or(0, |(id, _)| id + 1);
+        alerts.insert(
+            id,
+            Alert {
+                id,
+                product_id: product.id,
+                quantity: product.quantity,
+                threshold: product.alert_threshold,
+                timestamp: time(),
+                acknowledged: false,
+            },
+        );
+    });
+}
+
+// Reject writes based on an outdated version of a product
+fn check_version(product: &Product, expected_version: u64) -> Result<(), Error> {
+    if product.version != expected_version {
+        return Err(Error::VersionConflict {
+            expected: expected_version,
+            actual: product.version,
+        });
     }
+    Ok(())
 }
 
-// update function to edit a warehouse where only owners of warehouses can edit title, is_community, price and description. Non owners can only edit descriptions of communtiy warehouses. authorizations is by password
-#[ic_cdk::update]
-fn edit_warehouse(payload: EditWarehousePayload) -> Result<Warehouse, Error> {
-    let warehouse =
-        WAREHOUSE_STORAGE.with(|warehouses| warehouses.borrow().get(&payload.warehouse_id));
+// Quantity of a product after adding amount, rejecting overflows and exceeding its max stock
+fn restocked_quantity(product: &Product, amount: u32) -> Result<u32, Error> {
+    let quantity = product.quantity as u64 + amount as u64;
+    if product.max_stock != 0 && quantity > product.max_stock as u64 {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "product: {} would exceed its max stock of {} by {}",
+                product.name,
+                product.max_stock,
+                quantity - product.max_stock as u64
+            ),
+        });
+    }
+    u32::try_from(quantity).map_err(|_| Error::InvalidPayload {
+        msg: format!("quantity of product: {} would overflow", product.name),
+    })
+}
 
-    match warehouse {
-        Some(warehouse) => {
-            let new_warehouse = Warehouse {
-                name: payload.name,
-                ..warehouse.clone()
-            };
+// Total quantity of all products held in a warehouse
+fn warehouse_used_quantity(warehouse_id: u64) -> u64 {
+    PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, product)| product.warehouse_id == warehouse_id)
+            .map(|(_, product)| product.quantity as u64)
+            .sum()
+    })
+}
 
-            match WAREHOUSE_STORAGE
-                .with(|s| s.borrow_mut().insert(warehouse.id, new_warehouse.clone()))
-            {
-                Some(_) => Ok(new_warehouse),
-                None => Err(Error::InvalidPayload {
-                    msg: format!("Could not edit warehouse     title: {}", warehouse.name),
-                }),
-            }
+// Total volume of all products held in a warehouse
+fn warehouse_used_volume(warehouse_id: u64) -> u64 {
+    PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, product)| product.warehouse_id == warehouse_id)
+            .map(|(_, product)| product.quantity as u64 * product.volume_cm3 as u64)
+            .sum()
+    })
+}
+
+// Reject adding quantity to a warehouse that doesn't have the free capacity for it,
+// unit_volume is the volume of one unit and only matters for volume capacity
+fn check_warehouse_capacity(
+    warehouse: &Warehouse,
+    quantity: u64,
+    unit_volume: u32,
+) -> Result<(), Error> {
+    if warehouse.capacity_mode == CapacityMode::Volume {
+        if unit_volume == 0 {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "warehouse: {} counts capacity by volume, volume_cm3 must be set",
+                    warehouse.name
+                ),
+            });
         }
-        None => Err(Error::NotFound {
-            msg: format!("warehouse of id: {} not found", payload.warehouse_id),
-        }),
+        if warehouse.volume_capacity_cm3 == 0 {
+            return Ok(());
+        }
+        let free = warehouse
+            .volume_capacity_cm3
+            .saturating_sub(warehouse_used_volume(warehouse.id));
+        if quantity.saturating_mul(unit_volume as u64) > free {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "warehouse: {} only has capacity for {} more cm3",
+                    warehouse.name, free
+                ),
+            });
+        }
+        return Ok(());
+    }
+
+    if warehouse.capacity == 0 {
+        return Ok(());
     }
+    let free = (warehouse.capacity as u64).saturating_sub(warehouse_used_quantity(warehouse.id));
+    if quantity > free {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "warehouse: {} only has capacity for {} more units",
+                warehouse.name, free
+            ),
+        });
+    }
+    Ok(())
 }
 
-// Define an Error enum for handling errors
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    AlreadyInit { msg: String },
-    InvalidPayload { msg: String },
-    Unauthorized { msg: String },
+// Reject placing a product of a category in a warehouse that is inactive, doesn't allow
+// the category, is at its category limit or lacks the free capacity for its quantity
+fn check_warehouse_accepts(
+    warehouse: &Warehouse,
+    category: &str,
+    quantity: u64,
+    unit_volume: u32,
+) -> Result<(), Error> {
+    if !warehouse.is_active {
+        return Err(Error::InvalidPayload {
+            msg: format!("warehouse: {} is not active", warehouse.name),
+        });
+    }
+    if !warehouse.allowed_categories.is_empty()
+        && !warehouse
+            .allowed_categories
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(category))
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "warehouse: {} does not accept category: {}",
+                warehouse.name, category
+            ),
+        });
+    }
+    if warehouse.max_distinct_categories != 0 {
+        let held: BTreeSet<String> = PRODUCT_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .filter(|(_, product)| product.warehouse_id == warehouse.id)
+                .map(|(_, product)| product.category.to_lowercase())
+                .collect()
+        });
+        if !held.contains(&category.to_lowercase())
+            && held.len() as u64 >= warehouse.max_distinct_categories as u64
+        {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "warehouse: {} already holds its limit of {} categories",
+                    warehouse.name, warehouse.max_distinct_categories
+                ),
+            });
+        }
+    }
+    check_warehouse_capacity(warehouse, quantity, unit_volume)
 }
 
-// Candid generator for exporting the Candid interface
-ic_cdk::export_candid!();
+// Reject starting a new lot of quantity units in a warehouse, on top of what
+// check_warehouse_accepts checks the lot must meet the warehouse's minimum initial stock
+fn check_new_lot(
+    warehouse: &Warehouse,
+    category: &str,
+    quantity: u32,
+    unit_volume: u32,
+) -> Result<(), Error> {
+    check_warehouse_accepts(warehouse, category, quantity as u64, unit_volume)?;
+    if quantity < warehouse.min_initial_stock {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "warehouse: {} only takes in products with at least {} units",
+                warehouse.name, warehouse.min_initial_stock
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Number of products held per warehouse id
+fn product_counts_by_warehouse() -> BTreeMap<u64, u64> {
+    let mut counts = BTreeMap::new();
+    PRODUCT_STORAGE.with(|s| {
+        for (_, product) in s.borrow().iter() {
+            *counts.entry(product.warehouse_id).or_insert(0) += 1;
+        }
+    });
+    counts
+}
+
+// Most items any list query returns, larger results are cut and flagged as truncated
+const MAX_RESULTS: usize = 1000;
+
+// Every product that isn't archived, in id order
+fn listed_products() -> Vec<Product> {
+    PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| !product.archived)
+            .collect()
+    })
+}
+
+// Cut a product listing down to MAX_RESULTS, recording whether anything was dropped
+fn product_list(mut products: Vec<Product>) -> ProductList {
+    let truncated = products.len() > MAX_RESULTS;
+    products.truncate(MAX_RESULTS);
+    ProductList {
+        items: products,
+        truncated,
+    }
+}
+
+// Cut a warehouse listing down to MAX_RESULTS, recording whether anything was dropped
+fn warehouse_list(mut warehouses: Vec<Warehouse>) -> WarehouseList {
+    let truncated = warehouses.len() > MAX_RESULTS;
+    warehouses.truncate(MAX_RESULTS);
+    WarehouseList {
+        items: warehouses,
+        truncated,
+    }
+}
+
+// Largest encoded response we return, kept well below the 2MB message limit
+const MAX_RESPONSE_BYTES: usize = 1_500_000;
+
+// Reject records larger than their stable storage bound instead of trapping on insert
+fn check_stored_size<T: Storable + BoundedStorable>(record: &T, kind: &str) -> Result<(), Error> {
+    let size = record.to_bytes().len();
+    if size > T::MAX_SIZE as usize {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "{} of {} bytes is larger than the {} bytes it can be stored in",
+                kind,
+                size,
+                T::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Reject responses that would be too large to reply with instead of trapping
+fn check_response_size<T: CandidType>(items: &Vec<T>) -> Result<(), Error> {
+    let size = Encode!(items).map_or(usize::MAX, |bytes| bytes.len());
+    if size > MAX_RESPONSE_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "response of {} items is too large ({} bytes), use pagination instead",
+                items.len(),
+                size
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Struct for payload date used in update functions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct WarehousePayload {
+    name: String,
+    #[validate(length(min = 3), custom = "validate_address")]
+    address: String,
+    password: String,
+    #[validate(custom = "validate_city")]
+    city: String,
+    capacity: u32,
+    capacity_mode: CapacityMode,
+    volume_capacity_cm3: u64,
+    #[validate(custom = "validate_allowed_categories")]
+    allowed_categories: Vec<String>,
+    min_initial_stock: u32,
+    max_distinct_categories: u32,
+    #[validate(custom = "validate_contact_email")]
+    contact_email: String,
+    #[validate(custom = "validate_phone")]
+    contact_phone: String,
+    #[validate(custom = "validate_opening_hours")]
+    opening_hours: Vec<(u8, u32, u32)>,
+    #[validate(custom = "validate_coordinates")]
+    coordinates: Option<(f64, f64)>,
+    #[validate(custom = "validate_tags")]
+    tags: Vec<String>,
+    daily_dispense_limit: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct ProductPayload {
+    name: String,
+    #[validate(custom = "validate_label")]
+    category: String,
+    quantity: u32,
+    #[validate(length(min = 1), custom = "validate_label")]
+    unit_of_measure: String,
+    unit_price: u64,
+    cost_price: u64,
+    #[validate(custom = "validate_image_url")]
+    image_url: Option<String>,
+    alert_threshold: u32,
+    max_stock: u32,
+    safety_stock: u32,
+    // the category default is used when not provided
+    reorder_level: Option<u32>,
+    #[validate(custom = "validate_label")]
+    lot_number: String,
+    expires_at: u64,
+    weight_grams: u32,
+    volume_cm3: u32,
+    warehouse_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct EditProductPayload {
+    name: String,
+    product_id: u64,
+    // version of the product the edit was based on
+    expected_version: u64,
+    // left unchanged when not provided
+    #[validate(custom = "validate_image_url")]
+    image_url: Option<String>,
+    // never applied, an edit that sets it is rejected so that quantity only
+    // changes through add_product_to_warehouse and remove_product_from_warehouse
+    quantity: Option<u32>,
+}
+
+// Trim a string and collapse internal runs of whitespace into single spaces,
+// so " Main  Depot" and "Main Depot" are stored and compared the same
+fn normalize(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+impl WarehousePayload {
+    // payload with normalized name, address, city, categories and contacts
+    fn normalized(self) -> Self {
+        WarehousePayload {
+            name: normalize(&self.name),
+            address: normalize(&self.address),
+            city: normalize(&self.city),
+            allowed_categories: self
+                .allowed_categories
+                .iter()
+                .map(|c| normalize(c))
+                .collect(),
+            contact_email: self.contact_email.trim().to_string(),
+            contact_phone: normalize(&self.contact_phone),
+            tags: normalize_tags(&self.tags),
+            ..self
+        }
+    }
+}
+
+// Limits on the variable sized fields of a warehouse, in bytes or entries. together with
+// a name of at most MAX_NAME_BYTES they keep the largest possible warehouse within its
+// 1024 byte storage bound
+const MAX_ADDRESS_BYTES: usize = 96;
+const MAX_CITY_BYTES: usize = 48;
+const MAX_CONTACT_EMAIL_BYTES: usize = 64;
+const MAX_CONTACT_PHONE_BYTES: usize = 20;
+const MAX_ALLOWED_CATEGORIES: usize = 5;
+const MAX_OPENING_WINDOWS: usize = 14;
+// weekdays of opening hours run from 0 to LAST_WEEKDAY, minutes of the day below MINUTES_PER_DAY
+const LAST_WEEKDAY: u8 = 6;
+const MINUTES_PER_DAY: u32 = 1440;
+const MAX_WAREHOUSE_TAGS: usize = 5;
+const MAX_TAG_BYTES: usize = 24;
+
+// Validation error carrying a message, so the message can name the limit it enforces
+fn invalid_field(code: &'static str, message: String) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(Cow::Owned(message));
+    error
+}
+
+// Addresses are at most MAX_ADDRESS_BYTES long
+fn validate_address(address: &str) -> Result<(), ValidationError> {
+    if address.len() > MAX_ADDRESS_BYTES {
+        return Err(invalid_field(
+            "address",
+            format!("address must be at most {} bytes", MAX_ADDRESS_BYTES),
+        ));
+    }
+    Ok(())
+}
+
+// Cities are at most MAX_CITY_BYTES long
+fn validate_city(city: &str) -> Result<(), ValidationError> {
+    if city.len() > MAX_CITY_BYTES {
+        return Err(invalid_field(
+            "city",
+            format!("city must be at most {} bytes", MAX_CITY_BYTES),
+        ));
+    }
+    Ok(())
+}
+
+// Contact emails are optional, one that is given needs an '@' and is at most
+// MAX_CONTACT_EMAIL_BYTES long
+fn validate_contact_email(email: &str) -> Result<(), ValidationError> {
+    if !email.is_empty() && (!email.contains('@') || email.len() > MAX_CONTACT_EMAIL_BYTES) {
+        return Err(invalid_field(
+            "contact_email",
+            format!(
+                "contact_email must contain an '@' and be at most {} bytes",
+                MAX_CONTACT_EMAIL_BYTES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// A warehouse can be limited to at most MAX_ALLOWED_CATEGORIES categories
+fn validate_allowed_categories(categories: &[String]) -> Result<(), ValidationError> {
+    if categories.len() > MAX_ALLOWED_CATEGORIES
+        || categories
+            .iter()
+            .any(|category| category.len() > MAX_LABEL_BYTES)
+    {
+        return Err(invalid_field(
+            "allowed_categories",
+            format!(
+                "a warehouse can allow at most {} categories of at most {} bytes each",
+                MAX_ALLOWED_CATEGORIES, MAX_LABEL_BYTES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Phone numbers are optional, one that is given may only hold digits, '+' and spaces, and
+// is at most MAX_CONTACT_PHONE_BYTES long
+fn validate_phone(phone: &str) -> Result<(), ValidationError> {
+    if phone.len() > MAX_CONTACT_PHONE_BYTES
+        || !phone
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '+' || c == ' ')
+    {
+        return Err(invalid_field(
+            "contact_phone",
+            format!(
+                "contact_phone may only contain digits, '+' and spaces, at most {} of them",
+                MAX_CONTACT_PHONE_BYTES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Opening hours need a weekday of 0 to LAST_WEEKDAY and an opening minute before a closing
+// minute under MINUTES_PER_DAY, with at most MAX_OPENING_WINDOWS windows
+fn validate_opening_hours(hours: &[(u8, u32, u32)]) -> Result<(), ValidationError> {
+    if hours.len() > MAX_OPENING_WINDOWS
+        || hours.iter().any(|&(weekday, open, close)| {
+            weekday > LAST_WEEKDAY || open >= close || close >= MINUTES_PER_DAY
+        })
+    {
+        return Err(invalid_field(
+            "opening_hours",
+            format!(
+                "opening_hours need at most {} windows, each with a weekday of 0-{} and open < close < {}",
+                MAX_OPENING_WINDOWS, LAST_WEEKDAY, MINUTES_PER_DAY
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Coordinates need a latitude of -90 to 90 and a longitude of -180 to 180
+fn validate_coordinates(&(latitude, longitude): &(f64, f64)) -> Result<(), ValidationError> {
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err(ValidationError::new(
+            "coordinates need a latitude of -90 to 90 and a longitude of -180 to 180",
+        ));
+    }
+    Ok(())
+}
+
+// Lowercase and normalize tags, dropping blanks and duplicates
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let tags: BTreeSet<String> = tags
+        .iter()
+        .map(|tag| normalize(tag).to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.into_iter().collect()
+}
+
+// A warehouse can carry at most MAX_WAREHOUSE_TAGS tags of at most MAX_TAG_BYTES each
+fn validate_tags(tags: &[String]) -> Result<(), ValidationError> {
+    if tags.len() > MAX_WAREHOUSE_TAGS || tags.iter().any(|tag| tag.len() > MAX_TAG_BYTES) {
+        return Err(invalid_field(
+            "tags",
+            format!(
+                "a warehouse can have at most {} tags of at most {} bytes each",
+                MAX_WAREHOUSE_TAGS, MAX_TAG_BYTES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Longest name a product or warehouse may have, in bytes
+const MAX_NAME_BYTES: usize = 64;
+
+// Names must have at least the configured minimum number of characters and fit in MAX_NAME_BYTES
+fn validate_name(name: &str) -> Result<(), Error> {
+    let min = config().min_name_length;
+    if name.chars().count() < min as usize {
+        return Err(Error::InvalidPayload {
+            msg: format!("name: {} must be at least {} characters long", name, min),
+        });
+    }
+    if name.len() > MAX_NAME_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("name must be at most {} bytes long", MAX_NAME_BYTES),
+        });
+    }
+    Ok(())
+}
+
+// Longest category, unit of measure or lot number, in bytes
+const MAX_LABEL_BYTES: usize = 32;
+
+// Short product labels are bounded so the product record stays within its size bound
+fn validate_label(label: &str) -> Result<(), ValidationError> {
+    if label.len() > MAX_LABEL_BYTES {
+        return Err(invalid_field(
+            "label",
+            format!(
+                "category, unit_of_measure and lot_number must be at most {} bytes",
+                MAX_LABEL_BYTES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Image urls must be https and shorter than 512 characters
+fn validate_image_url(url: &str) -> Result<(), ValidationError> {
+    if !url.starts_with("https://") || url.len() >= 512 {
+        return Err(ValidationError::new(
+            "image_url must be an https url under 512 characters",
+        ));
+    }
+    Ok(())
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GetProductPayload {
+    product_id: u64,
+    amount: u32,
+    // lets a controller remove units reserved as safety stock
+    override_safety_stock: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct EditWarehousePayload {
+    warehouse_id: u64,
+    name: String,
+    // left unchanged when not provided
+    capacity: Option<u32>,
+    #[validate(custom = "validate_allowed_categories")]
+    allowed_categories: Option<Vec<String>>,
+    min_initial_stock: Option<u32>,
+    max_distinct_categories: Option<u32>,
+    #[validate(custom = "validate_contact_email")]
+    contact_email: Option<String>,
+    #[validate(custom = "validate_phone")]
+    contact_phone: Option<String>,
+    #[validate(custom = "validate_opening_hours")]
+    opening_hours: Option<Vec<(u8, u32, u32)>>,
+    #[validate(custom = "validate_coordinates")]
+    coordinates: Option<(f64, f64)>,
+    daily_dispense_limit: Option<u32>,
+}
+
+// Struct for a warehouse together with totals over the products it holds
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct WarehouseSummary {
+    warehouse: Warehouse,
+    product_count: u64,
+    total_quantity: u64,
+}
+
+// Struct for optional product filters, every provided filter must match
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ProductFilter {
+    category: Option<String>,
+    warehouse_id: Option<u64>,
+    min_quantity: Option<u32>,
+    max_quantity: Option<u32>,
+    name_contains: Option<String>,
+}
+
+// Struct describing the stock taken out for one order line
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RemovalReceipt {
+    product_id: u64,
+    removed: u32,
+    remaining: u32,
+    dry_run: bool,
+}
+
+// Struct holding a page of a copy of the inventory for backup and restore, the pages
+// of an export put together hold every product and warehouse
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Snapshot {
+    products: Vec<Product>,
+    warehouses: Vec<Warehouse>,
+    next_id: u64,
+    exported_at: u64,
+    // number of products and warehouses in the whole export
+    product_total: u64,
+    warehouse_total: u64,
+}
+
+// Struct for a product whose warehouse doesn't match storage
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct IntegrityIssue {
+    product_id: u64,
+    warehouse_id: u64,
+    issue: String,
+}
+
+// Struct reporting what clear_all removed and whether ids keep counting up
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ClearResult {
+    products_removed: u64,
+    warehouses_removed: u64,
+    ids_preserved: bool,
+}
+
+// Struct with a quick operational view of the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct HealthStatus {
+    product_count: u64,
+    warehouse_count: u64,
+    stable_memory_pages: u64,
+    cycles_balance: u128,
+    timestamp: u64,
+}
+
+// Struct reporting the net change in a product's quantity over a period. totals are
+// widened to 64 bits so large movements can't wrap the way u32 quantities would
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct QuantityDelta {
+    product_id: u64,
+    inbound: u64,
+    outbound: u64,
+    net: i64,
+}
+
+// Struct for one page of stock movements
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MovementPage {
+    items: Vec<StockMovement>,
+    // id of the last movement on the page, pass it as the cursor for the next page
+    next_cursor: Option<u64>,
+}
+
+// Struct for the margin of one product, margin_pct is the margin as a percentage of the sale price
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MarginEntry {
+    product_id: u64,
+    cost_price: u64,
+    sale_price: u64,
+    margin: i64,
+    margin_pct: f64,
+}
+
+// Struct describing both sides of a transfer between warehouses
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TransferReceipt {
+    // lot in the target warehouse the units were added to
+    target_product_id: u64,
+    transferred: u32,
+    source_remaining: u32,
+    target_total: u32,
+    timestamp: u64,
+}
+
+// Struct for one page of products together with the total number of products
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PagedProducts {
+    items: Vec<Product>,
+    total: u64,
+    offset: u64,
+    limit: u64,
+}
+
+// Struct for a product listing, truncated is set when more than MAX_RESULTS products matched
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ProductList {
+    items: Vec<Product>,
+    truncated: bool,
+}
+
+// Struct for a warehouse listing, truncated is set when more than MAX_RESULTS warehouses matched
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct WarehouseList {
+    items: Vec<Warehouse>,
+    truncated: bool,
+}
+
+// Struct for groups of duplicate products, truncated is set when whole groups were left out
+// to keep the listing within MAX_RESULTS products
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DuplicateGroups {
+    groups: Vec<Vec<Product>>,
+    truncated: bool,
+}
+
+// Struct for how full a warehouse is, in units or cm3 depending on its capacity mode
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct WarehouseUtilization {
+    warehouse_id: u64,
+    name: String,
+    used: u64,
+    capacity: u64,
+    utilization_pct: u64,
+    // how far used exceeds capacity, 0 when within capacity
+    overflow: u64,
+}
+
+// Struct for the products and warehouses matching a global search
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GlobalSearchResult {
+    products: Vec<Product>,
+    warehouses: Vec<Warehouse>,
+}
+
+// Update function to add a product
+#[ic_cdk::update]
+fn add_product(payload: ProductPayload) -> Result<Product, Error> {
+    require_authenticated()?;
+    let payload = ProductPayload {
+        name: normalize(&payload.name),
+        category: normalize(&payload.category),
+        ..payload
+    };
+    // validate payload
+    if let Err(errors) = payload.validate() {
+        return Err(Error::InvalidPayload {
+            msg: errors.to_string(),
+        });
+    }
+    validate_name(&payload.name)?;
+    if payload.safety_stock > payload.quantity {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "safety stock {} is above the quantity of {}",
+                payload.safety_stock, payload.quantity
+            ),
+        });
+    }
+    if payload.max_stock != 0 && payload.max_stock < payload.quantity {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "quantity {} is above the max stock of {}",
+                payload.quantity, payload.max_stock
+            ),
+        });
+    }
+
+    // get warehouse
+    let warehouse =
+        WAREHOUSE_STORAGE.with(|warehouses| warehouses.borrow().get(&payload.warehouse_id));
+    match warehouse {
+        Some(warehouse) => {
+            check_new_lot(
+                &warehouse,
+                &payload.category,
+                payload.quantity,
+                payload.volume_cm3,
+            )?;
+            // the id is only taken once the product is known to be valid
+            let id = peek_next_id();
+            let reorder_level = payload
+                .reorder_level
+                .unwrap_or_else(|| category_reorder_default(&payload.category));
+            let product = Product {
+                schema_version: PRODUCT_SCHEMA_VERSION,
+                id,
+                display_id: display_id(&config().product_id_prefix, id),
+                name: payload.name.clone(),
+                quantity: payload.quantity,
+                unit_of_measure: payload.unit_of_measure,
+                category: payload.category,
+                unit_price: payload.unit_price,
+                cost_price: payload.cost_price,
+                image_url: payload.image_url,
+                alert_threshold: payload.alert_threshold,
+                max_stock: payload.max_stock,
+                safety_stock: payload.safety_stock,
+                reorder_level,
+                lot_number: payload.lot_number,
+                expires_at: payload.expires_at,
+                total_dispensed: 0,
+                weight_grams: payload.weight_grams,
+                volume_cm3: payload.volume_cm3,
+                warehouse_id: warehouse.id,
+                added_at: time(),
+                re_stocked_at: time(),
+                updated_at: time(),
+                version: 0,
+                archived: false,
+            };
+            check_stored_size(&product, "product")?;
+            next_id()?;
+
+            match PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(id, product.clone())) {
+                None => {
+                    record_movement(id, product.quantity as i64, MovementReason::Add, None);
+                    Ok(product)
+                }
+                Some(_) => Err(Error::InvalidPayload {
+                    msg: format!("Could not add product name: {}", payload.name),
+                }),
+            }
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Warehouse of id: {} not found", payload.warehouse_id),
+        }),
+    }
+}
+
+// Define query function to get a product by ID, an unknown id comes back with the
+// nearest existing ids below and above it
+#[ic_cdk::query]
+fn get_product_by_id(id: u64) -> Result<Product, Error> {
+    PRODUCT_STORAGE.with(|products| {
+        let products = products.borrow();
+        match products.get(&id) {
+            Some(product) => Ok(product),
+            None => {
+                let below = products.iter_upper_bound(&id).next().map(|(key, _)| key);
+                let above = id
+                    .checked_add(1)
+                    .and_then(|next| products.range(next..).next().map(|(key, _)| key));
+                Err(Error::NotFoundWithSuggestions {
+                    msg: format!("product id:{} does not exist", id),
+                    nearest: below.into_iter().chain(above).collect(),
+                })
+            }
+        }
+    })
+}
+
+// get all products that aren't archived
+#[ic_cdk::query]
+fn get_all_products() -> Result<ProductList, Error> {
+    // Retrieve all products from the storage
+    let product_map: Vec<(u64, Product)> = PRODUCT_STORAGE.with(|s| s.borrow().iter().collect());
+    // Extract the products from the tuple and create a vector
+    let products: Vec<Product> = product_map
+        .into_iter()
+        .map(|(_, product)| product)
+        .filter(|product| !product.archived)
+        .collect();
+
+    match products.len() {
+        0 => Err(Error::NotFound {
+            msg: "no products found".to_string(),
+        }),
+        _ => {
+            let list = product_list(products);
+            check_response_size(&list.items)?;
+            Ok(list)
+        }
+    }
+}
+
+// get a page of products in id order with the total product count, at most 100 per page
+#[ic_cdk::query]
+fn get_products_page_with_total(offset: u64, limit: u64) -> PagedProducts {
+    let limit = limit.min(100);
+    PRODUCT_STORAGE.with(|s| {
+        let products = s.borrow();
+        let listed = || {
+            products
+                .iter()
+                .map(|(_, product)| product)
+                .filter(|product| !product.archived)
+        };
+        PagedProducts {
+            items: listed()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .collect(),
+            total: listed().count() as u64,
+            offset,
+            limit,
+        }
+    })
+}
+
+// get the id the next added product or warehouse will receive, without allocating it
+#[ic_cdk::query]
+fn peek_next_id() -> u64 {
+    ID_COUNTER.with(|counter| *counter.borrow().get())
+}
+
+// get the ids of all products in ascending order
+#[ic_cdk::query]
+fn get_product_ids() -> Vec<u64> {
+    PRODUCT_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect())
+}
+
+// function to remove a given quantity fo product from a warehouse while cheking if product is available and if warehouse has enough quantity
+#[ic_cdk::update]
+fn remove_product_from_warehouse(payload: GetProductPayload) -> Result<Product, Error> {
+    require_authenticated()?;
+    let product = PRODUCT_STORAGE.with(|products| products.borrow().get(&payload.product_id));
+    match product {
+        Some(product) => {
+            if product.quantity < payload.amount {
+                return Err(Error::InvalidPayload {
+                    msg: format!("Not enough quantity of product: {}", product.name),
+                });
+            }
+            check_safety_stock(&product, payload.amount, payload.override_safety_stock)?;
+            check_daily_dispense_limit(product.warehouse_id, payload.amount)?;
+
+            let new_product = Product {
+                quantity: product.quantity - payload.amount,
+                total_dispensed: product.total_dispensed + payload.amount as u64,
+                updated_at: time(),
+                version: product.version + 1,
+                ..product.clone()
+            };
+
+            match PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product.id, new_product.clone())) {
+                Some(_) => {
+                    record_movement(
+                        product.id,
+                        -(payload.amount as i64),
+                        MovementReason::Dispense,
+                        None,
+                    );
+                    record_daily_dispense(product.warehouse_id, payload.amount);
+                    check_stock_alert(&new_product);
+                    Ok(new_product)
+                }
+                None => Err(Error::InvalidPayload {
+                    msg: format!("Could not remove product name: {}", product.name),
+                }),
+            }
+        }
+        None => Err(Error::NotFound {
+            msg: format!("product of id: {} not found", payload.product_id),
+        }),
+    }
+}
+
+// function to add a given quantity fo product to a warehouse
+#[ic_cdk::update]
+fn add_product_to_warehouse(payload: GetProductPayload) -> Result<Product, Error> {
+    require_authenticated()?;
+    let product = PRODUCT_STORAGE.with(|products| products.borrow().get(&payload.product_id));
+    match product {
+        Some(product) => {
+            let quantity = restocked_quantity(&product, payload.amount)?;
+            if let Some(warehouse) =
+                WAREHOUSE_STORAGE.with(|s| s.borrow().get(&product.warehouse_id))
+            {
+                check_warehouse_capacity(&warehouse, payload.amount as u64, product.volume_cm3)?;
+            }
+            let new_product = Product {
+                quantity,
+                re_stocked_at: time(),
+                archived: false,
+                updated_at: time(),
+                version: product.version + 1,
+                ..product.clone()
+            };
+
+            match PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product.id, new_product.clone())) {
+                Some(_) => {
+                    record_movement(
+                        product.id,
+                        payload.amount as i64,
+                        MovementReason::Restock,
+                        None,
+                    );
+                    Ok(new_product)
+                }
+                None => Err(Error::InvalidPayload {
+                    msg: format!("Could not add product name: {}", product.name),
+                }),
+            }
+        }
+        None => Err(Error::NotFound {
+            msg: format!("product of id: {} not found", payload.product_id),
+        }),
+    }
+}
+
+// update function to edit a product
+#[ic_cdk::update]
+fn edit_product(payload: EditProductPayload) -> Result<Product, Error> {
+    require_authenticated()?;
+    let payload = EditProductPayload {
+        name: normalize(&payload.name),
+        ..payload
+    };
+    if let Err(errors) = payload.validate() {
+        return Err(Error::InvalidPayload {
+            msg: errors.to_string(),
+        });
+    }
+    validate_name(&payload.name)?;
+    if payload.quantity.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "quantity can't be edited, use add_product_to_warehouse or remove_product_from_warehouse".to_string(),
+        });
+    }
+
+    let product = PRODUCT_STORAGE.with(|products| products.borrow().get(&payload.product_id));
+
+    match product {
+        Some(product) => {
+            check_version(&product, payload.expected_version)?;
+            let new_product = Product {
+                name: payload.name,
+                image_url: payload.image_url.or(product.image_url.clone()),
+                updated_at: time(),
+                version: product.version + 1,
+                ..product.clone()
+            };
+            check_stored_size(&new_product, "product")?;
+
+            match PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product.id, new_product.clone())) {
+                Some(_) => Ok(new_product),
+                None => Err(Error::InvalidPayload {
+                    msg: format!("Could not edit product name: {}", product.name),
+                }),
+            }
+        }
+        None => Err(Error::NotFound {
+            msg: format!("product of id: {} not found", payload.product_id),
+        }),
+    }
+}
+
+// Most edits applied by one edit_products_batch call
+const MAX_EDIT_BATCH: usize = 100;
+
+// update function to apply several product edits independently, returning each edit's
+// result in order so that one failing edit doesn't stop the others
+#[ic_cdk::update]
+fn edit_products_batch(payloads: Vec<EditProductPayload>) -> Vec<Result<Product, String>> {
+    payloads
+        .into_iter()
+        .enumerate()
+        .map(|(index, payload)| {
+            if index >= MAX_EDIT_BATCH {
+                return Err(format!(
+                    "only the first {} edits of a batch are applied",
+                    MAX_EDIT_BATCH
+                ));
+            }
+            edit_product(payload).map_err(|err| err.to_string())
+        })
+        .collect()
+}
+
+// update function to set the unit price of a product, recording the change in the price history
+#[ic_cdk::update]
+fn update_unit_price(
+    product_id: u64,
+    unit_price: u64,
+    expected_version: u64,
+) -> Result<Product, Error> {
+    require_authenticated()?;
+    let product = PRODUCT_STORAGE.with(|products| products.borrow().get(&product_id));
+
+    match product {
+        Some(product) => {
+            check_version(&product, expected_version)?;
+            let new_product = Product {
+                unit_price,
+                updated_at: time(),
+                version: product.version + 1,
+                ..product.clone()
+            };
+
+            PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product.id, new_product.clone()));
+            record_price_change(product.id, product.unit_price, unit_price);
+            Ok(new_product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("product of id: {} not found", product_id),
+        }),
+    }
+}
+
+// Most entries apply_price_list takes in one call
+const MAX_PRICE_LIST_ENTRIES: usize = 500;
+
+// update function to apply a price list of (product id, new unit price) entries, each id
+// comes back as Ok when its price was applied or as Err when no such product exists.
+// entries that don't change a price leave the product and price history untouched
+#[ic_cdk::update]
+fn apply_price_list(entries: Vec<(u64, u64)>) -> Result<Vec<Result<u64, u64>>, Error> {
+    require_authenticated()?;
+    if entries.len() > MAX_PRICE_LIST_ENTRIES {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "a price list can have at most {} entries",
+                MAX_PRICE_LIST_ENTRIES
+            ),
+        });
+    }
+
+    Ok(entries
+        .into_iter()
+        .map(|(product_id, new_price)| {
+            let product = PRODUCT_STORAGE
+                .with(|s| s.borrow().get(&product_id))
+                .ok_or(product_id)?;
+            if product.unit_price != new_price {
+                let new_product = Product {
+                    unit_price: new_price,
+                    updated_at: time(),
+                    version: product.version + 1,
+                    ..product.clone()
+                };
+                PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product_id, new_product));
+                record_price_change(product_id, product.unit_price, new_price);
+            }
+            Ok(product_id)
+        })
+        .collect())
+}
+
+// update function to adjust the unit price of every product in a category by a number of basis points
+// e.g. +500 raises prices by 5%, -10000 or lower drops them to 0
+#[ic_cdk::update]
+fn adjust_prices_by_category(category: String, percent_bps: i32) -> Result<u64, Error> {
+    require_authenticated()?;
+    let query = normalize(&category).to_lowercase();
+    let products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.category.to_lowercase() == query)
+            .collect()
+    });
+
+    if products.is_empty() {
+        return Err(Error::NotFound {
+            msg: format!("No products for category: {} could be found", category),
+        });
+    }
+
+    // compute every new price first so that an overflow leaves all prices untouched
+    let factor = 10_000i64 + percent_bps as i64;
+    let mut adjusted: Vec<(Product, u64)> = Vec::with_capacity(products.len());
+    for product in products {
+        let new_price = if factor <= 0 {
+            0
+        } else {
+            u64::try_from(product.unit_price as u128 * factor as u128 / 10_000).map_err(|_| {
+                Error::InvalidPayload {
+                    msg: format!("Price of product: {} would overflow", product.name),
+                }
+            })?
+        };
+        adjusted.push((product, new_price));
+    }
+
+    let count = adjusted.len() as u64;
+    for (product, new_price) in adjusted {
+        let new_product = Product {
+            unit_price: new_price,
+            updated_at: time(),
+            version: product.version + 1,
+            ..product.clone()
+        };
+        PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product.id, new_product));
+        record_price_change(product.id, product.unit_price, new_price);
+    }
+
+    Ok(count)
+}
+
+// get the recorded price changes of a product, oldest first
+#[ic_cdk::query]
+fn get_price_history(product_id: u64) -> Vec<PriceChange> {
+    PRICE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .map(|(_, change)| change)
+            .filter(|change| change.product_id == product_id)
+            .collect()
+    })
+}
+
+// get products matching all of the provided filters
+#[ic_cdk::query]
+fn query_products(filter: ProductFilter) -> Result<ProductList, Error> {
+    let category = filter.category.map(|category| category.to_lowercase());
+    let name_contains = filter.name_contains.map(|name| name.to_lowercase());
+
+    let products = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                !product.archived
+                    && category
+                        .as_ref()
+                        .is_none_or(|category| product.category.to_lowercase() == *category)
+                    && filter
+                        .warehouse_id
+                        .is_none_or(|id| product.warehouse_id == id)
+                    && filter
+                        .min_quantity
+                        .is_none_or(|min| product.quantity >= min)
+                    && filter
+                        .max_quantity
+                        .is_none_or(|max| product.quantity <= max)
+                    && name_contains
+                        .as_ref()
+                        .is_none_or(|name| product.name.to_lowercase().contains(name))
+            })
+            .collect()
+    });
+
+    Ok(product_list(products))
+}
+
+// get the stock movements of a product, oldest first
+#[ic_cdk::query]
+fn get_product_movements(product_id: u64) -> Vec<StockMovement> {
+    MOVEMENT_STORAGE.with(|movements| {
+        movements
+            .borrow()
+            .iter()
+            .map(|(_, movement)| movement)
+            .filter(|movement| movement.product_id == product_id)
+            .collect()
+    })
+}
+
+// get the stock movements of a product recorded for one reason, oldest first
+#[ic_cdk::query]
+fn get_movements_by_reason(product_id: u64, reason: MovementReason) -> Vec<StockMovement> {
+    get_product_movements(product_id)
+        .into_iter()
+        .filter(|movement| movement.reason == reason)
+        .collect()
+}
+
+// reconstruct a product's quantity at a point in time by replaying its movements
+#[ic_cdk::query]
+fn get_quantity_as_of(product_id: u64, timestamp_ns: u64) -> Result<u32, Error> {
+    get_product_by_id(product_id)?;
+
+    let quantity: i64 = get_product_movements(product_id)
+        .iter()
+        .filter(|movement| movement.timestamp <= timestamp_ns)
+        .map(|movement| movement.delta)
+        .sum();
+
+    Ok(quantity.clamp(0, u32::MAX as i64) as u32)
+}
+
+// Most movements returned in one page
+const MAX_MOVEMENT_PAGE: u64 = 100;
+
+// get one page of a product's stock movements in id order, those after the
+// after_movement_id cursor (none for the first page), next_cursor is set while
+// more movements may follow
+#[ic_cdk::query]
+fn get_movements_paginated(
+    product_id: u64,
+    after_movement_id: Option<u64>,
+    limit: u64,
+) -> MovementPage {
+    let limit = limit.min(MAX_MOVEMENT_PAGE) as usize;
+    let start = match after_movement_id {
+        Some(id) => Bound::Excluded(id),
+        None => Bound::Unbounded,
+    };
+
+    let items: Vec<StockMovement> = MOVEMENT_STORAGE.with(|movements| {
+        movements
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, movement)| movement)
+            .filter(|movement| movement.product_id == product_id)
+            .take(limit)
+            .collect()
+    });
+
+    let next_cursor = match items.last() {
+        Some(last) if items.len() == limit => Some(last.id),
+        _ => None,
+    };
+
+    MovementPage { items, next_cursor }
+}
+
+// suggest how much of a product to reorder: twice the outbound quantity of the
+// trailing window minus the current stock, never below 0
+#[ic_cdk::query]
+fn suggest_reorder_quantity(product_id: u64, window_ns: u64) -> Result<u32, Error> {
+    let product = get_product_by_id(product_id)?;
+    let since = time().saturating_sub(window_ns);
+
+    let consumed: u64 = get_product_movements(product_id)
+        .iter()
+        .filter(|movement| movement.delta < 0 && movement.timestamp >= since)
+        .map(|movement| movement.delta.unsigned_abs())
+        .sum();
+
+    let suggestion = consumed
+        .saturating_mul(2)
+        .saturating_sub(product.quantity as u64);
+    Ok(u32::try_from(suggestion).unwrap_or(u32::MAX))
+}
+
+// function to take the stock for every line of an order at once, either all lines succeed or nothing changes.
+// with dry_run the availability checks run and receipts are returned without touching the stock
+#[ic_cdk::update]
+fn consume_for_order(
+    items: Vec<GetProductPayload>,
+    dry_run: bool,
+) -> Result<Vec<RemovalReceipt>, Error> {
+    require_authenticated()?;
+    if items.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "order has no items".to_string(),
+        });
+    }
+
+    // track the remaining stock per product so repeated lines are checked cumulatively
+    let mut remaining: BTreeMap<u64, Product> = BTreeMap::new();
+    let mut receipts = Vec::with_capacity(items.len());
+    // units taken from each warehouse, checked against its daily dispense limit as a whole
+    let mut dispensed: BTreeMap<u64, u32> = BTreeMap::new();
+    for item in &items {
+        let product = match remaining.entry(item.product_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(get_product_by_id(item.product_id)?),
+        };
+        if product.quantity < item.amount {
+            return Err(Error::InvalidPayload {
+                msg: format!("Not enough quantity of product: {}", product.name),
+            });
+        }
+        check_safety_stock(product, item.amount, item.override_safety_stock)?;
+        let taken = dispensed.entry(product.warehouse_id).or_insert(0);
+        *taken = taken.saturating_add(item.amount);
+        product.quantity -= item.amount;
+        product.total_dispensed += item.amount as u64;
+        product.updated_at = time();
+        product.version += 1;
+        receipts.push(RemovalReceipt {
+            product_id: item.product_id,
+            removed: item.amount,
+            remaining: product.quantity,
+            dry_run,
+        });
+    }
+    for (&warehouse_id, &amount) in &dispensed {
+        check_daily_dispense_limit(warehouse_id, amount)?;
+    }
+
+    if !dry_run {
+        PRODUCT_STORAGE.with(|s| {
+            let mut products = s.borrow_mut();
+            for product in remaining.values() {
+                products.insert(product.id, product.clone());
+            }
+        });
+        for item in &items {
+            record_movement(
+                item.product_id,
+                -(item.amount as i64),
+                MovementReason::Dispense,
+                Some("order".to_string()),
+            );
+        }
+        for (warehouse_id, amount) in dispensed {
+            record_daily_dispense(warehouse_id, amount);
+        }
+        remaining.values().for_each(check_stock_alert);
+    }
+
+    Ok(receipts)
+}
+
+// Most lines a single delivery may have, keeps the delivery record within its size bound
+const MAX_DELIVERY_ITEMS: usize = 50;
+
+// function to restock every line of a supplier delivery at once. a delivery id that was
+// already processed isn't applied again, the products it restocked are returned instead,
+// so a retried call is safe. nothing is restocked unless every line fits
+#[ic_cdk::update]
+fn record_delivery(
+    delivery_id: String,
+    items: Vec<GetProductPayload>,
+) -> Result<Vec<Product>, Error> {
+    require_authenticated()?;
+    let delivery_id = delivery_id.trim().to_string();
+    if delivery_id.is_empty() || delivery_id.len() > MAX_DELIVERY_ID_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "delivery_id must be between 1 and {} bytes",
+                MAX_DELIVERY_ID_BYTES
+            ),
+        });
+    }
+
+    let key = DeliveryId(delivery_id.clone());
+    let processed = DELIVERY_LOG.with(|log| log.borrow().get(&key));
+    if let Some(delivery) = processed {
+        let ids: BTreeSet<u64> = delivery.items.iter().map(|(id, _)| *id).collect();
+        return Ok(ids
+            .into_iter()
+            .filter_map(|id| PRODUCT_STORAGE.with(|s| s.borrow().get(&id)))
+            .collect());
+    }
+
+    if items.is_empty() || items.len() > MAX_DELIVERY_ITEMS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "a delivery must have between 1 and {} items",
+                MAX_DELIVERY_ITEMS
+            ),
+        });
+    }
+
+    // restock a working copy per product so repeated lines add up
+    let mut restocked: BTreeMap<u64, Product> = BTreeMap::new();
+    // units, volume and whether any line lacks a volume, per receiving warehouse
+    let mut incoming: BTreeMap<u64, (u64, u64, bool)> = BTreeMap::new();
+    for item in &items {
+        let product = match restocked.entry(item.product_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(get_product_by_id(item.product_id)?),
+        };
+        product.quantity = restocked_quantity(product, item.amount)?;
+        product.re_stocked_at = time();
+        product.archived = false;
+        product.updated_at = time();
+        product.version += 1;
+
+        let totals = incoming
+            .entry(product.warehouse_id)
+            .or_insert((0, 0, false));
+        totals.0 += item.amount as u64;
+        totals.1 += item.amount as u64 * product.volume_cm3 as u64;
+        totals.2 |= product.volume_cm3 == 0;
+    }
+    for (warehouse_id, (units, volume, missing_volume)) in incoming {
+        if let Some(warehouse) = WAREHOUSE_STORAGE.with(|s| s.borrow().get(&warehouse_id)) {
+            // the totals are already in the warehouse's unit, so each counts once
+            match warehouse.capacity_mode {
+                CapacityMode::Units => check_warehouse_capacity(&warehouse, units, 1)?,
+                CapacityMode::Volume => {
+                    check_warehouse_capacity(&warehouse, volume, u32::from(!missing_volume))?
+                }
+            }
+        }
+    }
+
+    PRODUCT_STORAGE.with(|s| {
+        let mut products = s.borrow_mut();
+        for product in restocked.values() {
+            products.insert(product.id, product.clone());
+        }
+    });
+    for item in &items {
+        record_movement(
+            item.product_id,
+            item.amount as i64,
+            MovementReason::Restock,
+            None,
+        );
+    }
+    DELIVERY_LOG.with(|log| {
+        log.borrow_mut().insert(
+            key,
+            Delivery {
+                delivery_id,
+                items: items
+                    .iter()
+                    .map(|item| (item.product_id, item.amount))
+                    .collect(),
+                processed_at: time(),
+            },
+        )
+    });
+
+    Ok(restocked.into_values().collect())
+}
+
+// get products that were never restocked. add_product sets added_at and re_stocked_at
+// to the same time and only restocking moves re_stocked_at, so equality means "never restocked"
+#[ic_cdk::query]
+fn get_never_restocked_products() -> Result<ProductList, Error> {
+    let products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| !product.archived && product.added_at == product.re_stocked_at)
+            .collect()
+    });
+
+    match products.len() {
+        0 => Err(Error::NotFound {
+            msg: "no never restocked products found".to_string(),
+        }),
+        _ => Ok(product_list(products)),
+    }
+}
+
+// get a product by ID like get_product_by_id, recording the read in the access log when
+// read logging is enabled. queries can't persist state, hence the update
+#[ic_cdk::update]
+fn get_product_logged(id: u64) -> Result<Product, Error> {
+    require_authenticated()?;
+    if config().log_reads {
+        ACCESS_LOG.with(|log| {
+            let mut log = log.borrow_mut();
+            let key = log.last_key_value().map_or(0, |(key, _)| key + 1);
+            log.insert(
+                key,
+                AccessLogEntry {
+                    product_id: id,
+                    caller: caller(),
+                    timestamp: time(),
+                },
+            );
+            // keep only the most recent entries
+            while log.len() > ACCESS_LOG_CAPACITY {
+                let oldest = log.first_key_value().map(|(key, _)| key).unwrap();
+                log.remove(&oldest);
+            }
+        });
+    }
+
+    get_product_by_id(id)
+}
+
+// get all low stock alerts, oldest first
+#[ic_cdk::query]
+fn get_alerts() -> Vec<Alert> {
+    ALERT_STORAGE.with(|alerts| alerts.borrow().iter().map(|(_, alert)| alert).collect())
+}
+
+// mark a low stock alert as acknowledged
+#[ic_cdk::update]
+fn acknowledge_alert(alert_id: u64) -> Result<Alert, Error> {
+    require_authenticated()?;
+    match ALERT_STORAGE.with(|alerts| alerts.borrow().get(&alert_id)) {
+        Some(alert) => {
+            let acknowledged = Alert {
+                acknowledged: true,
+                ..alert
+            };
+            ALERT_STORAGE.with(|alerts| alerts.borrow_mut().insert(alert_id, acknowledged.clone()));
+            Ok(acknowledged)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("alert of id: {} not found", alert_id),
+        }),
+    }
+}
+
+// get the products with the highest (or lowest) on-hand value, quantity times unit price.
+// at most 100 products are returned, ties are ordered by id
+#[ic_cdk::query]
+fn get_products_by_total_value(descending: bool, limit: u64) -> Vec<Product> {
+    let mut products = listed_products();
+
+    let value = |product: &Product| product.quantity as u128 * product.unit_price as u128;
+    products.sort_by(|a, b| {
+        let order = if descending {
+            value(b).cmp(&value(a))
+        } else {
+            value(a).cmp(&value(b))
+        };
+        order.then(a.id.cmp(&b.id))
+    });
+    products.truncate(limit.min(100) as usize);
+    products
+}
+
+// get the margin of every product, sale price minus cost price, lowest margin first
+#[ic_cdk::query]
+fn get_margin_report() -> Vec<MarginEntry> {
+    let mut entries: Vec<MarginEntry> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| {
+                // sale can be below cost, so the margin may be negative
+                let margin = (product.unit_price as i128 - product.cost_price as i128)
+                    .clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+                let margin_pct = if product.unit_price == 0 {
+                    0.0
+                } else {
+                    margin as f64 * 100.0 / product.unit_price as f64
+                };
+                MarginEntry {
+                    product_id: product.id,
+                    cost_price: product.cost_price,
+                    sale_price: product.unit_price,
+                    margin,
+                    margin_pct,
+                }
+            })
+            .collect()
+    });
+    entries.sort_by_key(|entry| (entry.margin, entry.product_id));
+    entries
+}
+
+// get (product id, quantity) pairs sorted by id, optionally only for one warehouse
+#[ic_cdk::query]
+fn get_availability_map(warehouse_id: Option<u64>) -> Vec<(u64, u32)> {
+    PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, product)| warehouse_id.is_none_or(|id| product.warehouse_id == id))
+            .map(|(id, product)| (id, product.quantity))
+            .collect()
+    })
+}
+
+// Number of single character edits needed to turn one string into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// search products by name content. with fuzzy, names within an edit distance of 2
+// of the query match instead, closest first
+#[ic_cdk::query]
+fn search_products(query: String, fuzzy: bool) -> Result<ProductList, Error> {
+    let query = query.to_lowercase();
+    let products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| !product.archived)
+            .collect()
+    });
+
+    let matches: Vec<Product> = if fuzzy {
+        let mut ranked: Vec<(usize, Product)> = products
+            .into_iter()
+            .map(|product| (edit_distance(&product.name.to_lowercase(), &query), product))
+            .filter(|(distance, _)| *distance <= 2)
+            .collect();
+        ranked.sort_by_key(|(distance, _)| *distance);
+        ranked.into_iter().map(|(_, product)| product).collect()
+    } else {
+        products
+            .into_iter()
+            .filter(|product| product.name.to_lowercase().contains(&query))
+            .collect()
+    };
+
+    match matches.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("No products for name: {} could be found", query),
+        }),
+        _ => Ok(product_list(matches)),
+    }
+}
+
+// get products with no activity (restock or other change) within the given period, oldest first
+#[ic_cdk::query]
+fn get_stale_products(older_than_ns: u64) -> Result<ProductList, Error> {
+    let cutoff = time().saturating_sub(older_than_ns);
+    let last_activity = |product: &Product| product.re_stocked_at.max(product.updated_at);
+
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| !product.archived && last_activity(product) < cutoff)
+            .collect()
+    });
+    products.sort_by_key(last_activity);
+
+    match products.len() {
+        0 => Err(Error::NotFound {
+            msg: "no stale products found".to_string(),
+        }),
+        _ => Ok(product_list(products)),
+    }
+}
+
+// function to move a whole product record to another warehouse, which must be active,
+// accept the product's category and have capacity for its full quantity
+#[ic_cdk::update]
+fn move_product_to_warehouse(product_id: u64, target_warehouse_id: u64) -> Result<Product, Error> {
+    require_authenticated()?;
+    let product = get_product_by_id(product_id)?;
+    // the product's own quantity would otherwise count against the capacity twice
+    if product.warehouse_id == target_warehouse_id {
+        return Err(Error::InvalidPayload {
+            msg: "source and target warehouse are the same".to_string(),
+        });
+    }
+    let target = WAREHOUSE_STORAGE
+        .with(|s| s.borrow().get(&target_warehouse_id))
+        .ok_or(Error::InvalidPayload {
+            msg: format!("warehouse of id: {} not found", target_warehouse_id),
+        })?;
+    check_warehouse_accepts(
+        &target,
+        &product.category,
+        product.quantity as u64,
+        product.volume_cm3,
+    )?;
+
+    let new_product = Product {
+        warehouse_id: target.id,
+        updated_at: time(),
+        version: product.version + 1,
+        ..product
+    };
+    PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product_id, new_product.clone()));
+    Ok(new_product)
+}
+
+// function to transfer part of a product's quantity to another warehouse. the units join
+// the target's lot with the same name, category and lot number, or a new lot is created
+// there if it meets the target's minimum initial stock. the move is recorded as an
+// outbound and an inbound movement
+#[ic_cdk::update]
+fn transfer_product(
+    product_id: u64,
+    target_warehouse_id: u64,
+    amount: u32,
+) -> Result<TransferReceipt, Error> {
+    require_authenticated()?;
+    let source = get_product_by_id(product_id)?;
+    if source.warehouse_id == target_warehouse_id {
+        return Err(Error::InvalidPayload {
+            msg: "source and target warehouse are the same".to_string(),
+        });
+    }
+    if amount == 0 || amount > source.quantity {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "can't transfer {} of product: {}, {} available",
+                amount, source.name, source.quantity
+            ),
+        });
+    }
+    check_safety_stock(&source, amount, false)?;
+    let target_warehouse = get_warehouse_by_id(target_warehouse_id)?;
+
+    let existing = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .find(|product| {
+                product.warehouse_id == target_warehouse_id
+                    && product.name.eq_ignore_ascii_case(&source.name)
+                    && product.category.eq_ignore_ascii_case(&source.category)
+                    && product.lot_number == source.lot_number
+            })
+    });
+    let target = match existing {
+        Some(product) => {
+            check_warehouse_accepts(
+                &target_warehouse,
+                &source.category,
+                amount as u64,
+                source.volume_cm3,
+            )?;
+            Product {
+                quantity: restocked_quantity(&product, amount)?,
+                re_stocked_at: time(),
+                archived: false,
+                updated_at: time(),
+                version: product.version + 1,
+                ..product
+            }
+        }
+        None => {
+            check_new_lot(
+                &target_warehouse,
+                &source.category,
+                amount,
+                source.volume_cm3,
+            )?;
+            if source.max_stock != 0 && amount > source.max_stock {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "a lot of product: {} can hold at most {}",
+                        source.name, source.max_stock
+                    ),
+                });
+            }
+            let id = next_id()?;
+            Product {
+                id,
+                display_id: display_id(&config().product_id_prefix, id),
+                quantity: amount,
+                // the reservation stays with the source lot, like for a clone
+                safety_stock: 0,
+                total_dispensed: 0,
+                warehouse_id: target_warehouse.id,
+                added_at: time(),
+                re_stocked_at: time(),
+                updated_at: time(),
+                version: 0,
+                archived: false,
+                ..source.clone()
+            }
+        }
+    };
+    let new_source = Product {
+        quantity: source.quantity - amount,
+        updated_at: time(),
+        version: source.version + 1,
+        ..source
+    };
+
+    PRODUCT_STORAGE.with(|s| {
+        let mut products = s.borrow_mut();
+        products.insert(new_source.id, new_source.clone());
+        products.insert(target.id, target.clone());
+    });
+    record_movement(
+        new_source.id,
+        -(amount as i64),
+        MovementReason::TransferOut,
+        None,
+    );
+    record_movement(target.id, amount as i64, MovementReason::TransferIn, None);
+    check_stock_alert(&new_source);
+
+    Ok(TransferReceipt {
+        target_product_id: target.id,
+        transferred: amount,
+        source_remaining: new_source.quantity,
+        target_total: target.quantity,
+        timestamp: time(),
+    })
+}
+
+// Longest reconcile note in bytes, leaves the rest of the movement record room within its size bound
+const MAX_NOTE_BYTES: usize = 200;
+
+// function for a warehouse owner or controller to set a product's quantity to a physical
+// count, the difference goes into the ledger with the note as its reason. a higher count
+// is checked against max_stock and the warehouse capacity like a restock
+#[ic_cdk::update]
+fn reconcile_product(
+    product_id: u64,
+    counted_quantity: u32,
+    note: String,
+) -> Result<Product, Error> {
+    require_authenticated()?;
+    let product = get_product_by_id(product_id)?;
+    let warehouse = WAREHOUSE_STORAGE.with(|s| s.borrow().get(&product.warehouse_id));
+    match &warehouse {
+        Some(warehouse) => require_warehouse_owner(warehouse)?,
+        None => require_controller()?,
+    }
+    let note = normalize(&note);
+    if note.len() > MAX_NOTE_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("note must be at most {} bytes", MAX_NOTE_BYTES),
+        });
+    }
+    // a count above the stored quantity brings units in, which have to fit like a restock
+    if counted_quantity > product.quantity {
+        let amount = counted_quantity - product.quantity;
+        restocked_quantity(&product, amount)?;
+        if let Some(warehouse) = &warehouse {
+            check_warehouse_capacity(warehouse, amount as u64, product.volume_cm3)?;
+        }
+    }
+
+    let delta = counted_quantity as i64 - product.quantity as i64;
+    let new_product = Product {
+        quantity: counted_quantity,
+        updated_at: time(),
+        version: product.version + 1,
+        ..product
+    };
+    PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product_id, new_product.clone()));
+    record_movement(product_id, delta, MovementReason::Reconcile, Some(note));
+    if delta < 0 {
+        check_stock_alert(&new_product);
+    }
+    Ok(new_product)
+}
+
+// function to start a new product from an existing one, copying everything but the name,
+// warehouse, quantity, history and timestamps. the new product starts empty, so a warehouse
+// with a minimum initial stock doesn't take it
+#[ic_cdk::update]
+fn clone_product(
+    product_id: u64,
+    new_name: String,
+    new_warehouse_id: u64,
+) -> Result<Product, Error> {
+    require_authenticated()?;
+    let source = get_product_by_id(product_id)?;
+    let new_name = normalize(&new_name);
+    validate_name(&new_name)?;
+    let warehouse = get_warehouse_by_id(new_warehouse_id)?;
+    check_new_lot(&warehouse, &source.category, 0, source.volume_cm3)?;
+
+    // the id is only taken once the product is known to fit
+    let id = peek_next_id();
+    let product = Product {
+        schema_version: PRODUCT_SCHEMA_VERSION,
+        id,
+        display_id: display_id(&config().product_id_prefix, id),
+        name: new_name,
+        quantity: 0,
+        // the reservation can't be held without stock
+        safety_stock: 0,
+        total_dispensed: 0,
+        warehouse_id: warehouse.id,
+        added_at: time(),
+        re_stocked_at: time(),
+        updated_at: time(),
+        version: 0,
+        archived: false,
+        ..source
+    };
+    check_stored_size(&product, "product")?;
+    next_id()?;
+    PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(id, product.clone()));
+    Ok(product)
+}
+
+// get a product by its human readable display id
+#[ic_cdk::query]
+fn get_product_by_display_id(display_id: String) -> Result<Product, Error> {
+    PRODUCT_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, product)| product)
+                .find(|product| product.display_id == display_id)
+        })
+        .ok_or(Error::NotFound {
+            msg: format!("product display id:{} does not exist", display_id),
+        })
+}
+
+// function to top a product up to a target level, products already at or above it are returned unchanged
+#[ic_cdk::update]
+fn restock_to_level(product_id: u64, target_level: u32) -> Result<Product, Error> {
+    require_authenticated()?;
+    let product = get_product_by_id(product_id)?;
+    if product.quantity >= target_level {
+        return Ok(product);
+    }
+
+    let amount = target_level - product.quantity;
+    let quantity = restocked_quantity(&product, amount)?;
+    if let Some(warehouse) = WAREHOUSE_STORAGE.with(|s| s.borrow().get(&product.warehouse_id)) {
+        check_warehouse_capacity(&warehouse, amount as u64, product.volume_cm3)?;
+    }
+
+    let new_product = Product {
+        quantity,
+        re_stocked_at: time(),
+        archived: false,
+        updated_at: time(),
+        version: product.version + 1,
+        ..product
+    };
+    PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product_id, new_product.clone()));
+    record_movement(product_id, amount as i64, MovementReason::Restock, None);
+    Ok(new_product)
+}
+
+// get (bucket start, count) pairs of product additions, grouping added_at into buckets of bucket_ns
+#[ic_cdk::query]
+fn get_additions_histogram(bucket_ns: u64) -> Result<Vec<(u64, u64)>, Error> {
+    if bucket_ns == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "bucket_ns must be greater than 0".to_string(),
+        });
+    }
+
+    let mut buckets: BTreeMap<u64, u64> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|s| {
+        for (_, product) in s.borrow().iter() {
+            let start = product.added_at - product.added_at % bucket_ns;
+            *buckets.entry(start).or_insert(0) += 1;
+        }
+    });
+
+    Ok(buckets.into_iter().collect())
+}
+
+// Start of the UTC calendar month a nanosecond timestamp falls in, using the
+// days-to-civil conversion from http://howardhinnant.github.io/date_algorithms.html
+fn month_start_ns(timestamp_ns: u64) -> u64 {
+    const NS_PER_DAY: u64 = 86_400_000_000_000;
+    // day within the 400 year cycle, counted from March 1st so leap days fall last
+    let day_of_era = (timestamp_ns / NS_PER_DAY + 719_468) % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5;
+    (timestamp_ns / NS_PER_DAY - day_of_month) * NS_PER_DAY
+}
+
+// get (month start, product count, total quantity) for the products expiring in each UTC
+// calendar month, sorted by month. products without an expiry are left out
+#[ic_cdk::query]
+fn get_expiry_buckets() -> Vec<(u64, u64, u64)> {
+    let mut buckets: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|s| {
+        for (_, product) in s
+            .borrow()
+            .iter()
+            .filter(|(_, product)| product.expires_at != 0)
+        {
+            let bucket = buckets
+                .entry(month_start_ns(product.expires_at))
+                .or_insert((0, 0));
+            bucket.0 += 1;
+            bucket.1 += product.quantity as u64;
+        }
+    });
+
+    buckets
+        .into_iter()
+        .map(|(month, (count, quantity))| (month, count, quantity))
+        .collect()
+}
+
+// function to dispense an item from a warehouse first-expired-first-out, taking from the
+// lots that expire soonest before later ones and lots without expiry last. lots that have
+// already expired are never dispensed
+#[ic_cdk::update]
+fn dispense_fefo(
+    product_name: String,
+    warehouse_id: u64,
+    amount: u32,
+) -> Result<Vec<RemovalReceipt>, Error> {
+    require_authenticated()?;
+    let name = normalize(&product_name).to_lowercase();
+    let now = time();
+    let mut lots: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.warehouse_id == warehouse_id
+                    && product.quantity > product.safety_stock
+                    && (product.expires_at == 0 || product.expires_at > now)
+                    && product.name.to_lowercase() == name
+            })
+            .collect()
+    });
+    lots.sort_by_key(|lot| (lot.expires_at == 0, lot.expires_at, lot.id));
+
+    // units reserved as safety stock are never dispensed
+    let available: u64 = lots
+        .iter()
+        .map(|lot| (lot.quantity - lot.safety_stock) as u64)
+        .sum();
+    if available < amount as u64 {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Not enough quantity of product: {}, {} available",
+                product_name, available
+            ),
+        });
+    }
+    check_daily_dispense_limit(warehouse_id, amount)?;
+
+    let mut receipts = Vec::new();
+    let mut outstanding = amount;
+    for lot in lots {
+        if outstanding == 0 {
+            break;
+        }
+        let taken = outstanding.min(lot.quantity - lot.safety_stock);
+        outstanding -= taken;
+
+        let new_lot = Product {
+            quantity: lot.quantity - taken,
+            total_dispensed: lot.total_dispensed + taken as u64,
+            updated_at: time(),
+            version: lot.version + 1,
+            ..lot
+        };
+        PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(new_lot.id, new_lot.clone()));
+        record_movement(new_lot.id, -(taken as i64), MovementReason::Dispense, None);
+        check_stock_alert(&new_lot);
+        receipts.push(RemovalReceipt {
+            product_id: new_lot.id,
+            removed: taken,
+            remaining: new_lot.quantity,
+            dry_run: false,
+        });
+    }
+    record_daily_dispense(warehouse_id, amount);
+
+    Ok(receipts)
+}
+
+// get the products with the highest cumulative dispensed quantity, at most 100
+#[ic_cdk::query]
+fn get_top_dispensed_products(limit: u64) -> Vec<Product> {
+    let mut products = listed_products();
+    products.sort_by_key(|product| Reverse(product.total_dispensed));
+    products.truncate(limit.min(100) as usize);
+    products
+}
+
+// search the products of one warehouse by name content
+#[ic_cdk::query]
+fn search_products_in_warehouse(warehouse_id: u64, query: String) -> Result<ProductList, Error> {
+    get_warehouse_by_id(warehouse_id)?;
+    let query = query.to_lowercase();
+
+    Ok(product_list(PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                !product.archived
+                    && product.warehouse_id == warehouse_id
+                    && product.name.to_lowercase().contains(&query)
+            })
+            .collect()
+    })))
+}
+
+// admin function to undo the most recent movement of a product that hasn't been undone yet.
+// the movement is kept and marked reversed, and a reversing entry is appended to the ledger.
+// movements that didn't change the quantity are passed over, and transfers can't be undone
+// from one side, giving units back goes through the same checks as a restock
+#[ic_cdk::update]
+fn undo_last_movement(product_id: u64) -> Result<Product, Error> {
+    require_authenticated()?;
+    require_controller()?;
+    let product = get_product_by_id(product_id)?;
+
+    let movement = get_product_movements(product_id)
+        .into_iter()
+        .rev()
+        .find(|movement| !movement.reversed && movement.reverses.is_none() && movement.delta != 0)
+        .ok_or(Error::NotFound {
+            msg: format!("no movement to undo for product of id: {}", product_id),
+        })?;
+    if matches!(
+        movement.reason,
+        MovementReason::TransferIn | MovementReason::TransferOut
+    ) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "movement: {} is one side of a transfer and can't be undone alone",
+                movement.id
+            ),
+        });
+    }
+
+    let out_of_range = || Error::InvalidPayload {
+        msg: format!(
+            "undoing movement: {} would take product: {} out of range",
+            movement.id, product.name
+        ),
+    };
+    let quantity = if movement.delta < 0 {
+        let amount = u32::try_from(movement.delta.unsigned_abs()).map_err(|_| out_of_range())?;
+        let quantity = restocked_quantity(&product, amount)?;
+        if let Some(warehouse) = WAREHOUSE_STORAGE.with(|s| s.borrow().get(&product.warehouse_id)) {
+            check_warehouse_capacity(&warehouse, amount as u64, product.volume_cm3)?;
+        }
+        quantity
+    } else {
+        u32::try_from(product.quantity as i64 - movement.delta).map_err(|_| out_of_range())?
+    };
+    // undoing a dispense gives back the dispensed units
+    let total_dispensed = if movement.reason == MovementReason::Dispense {
+        product
+            .total_dispensed
+            .saturating_sub(movement.delta.unsigned_abs())
+    } else {
+        product.total_dispensed
+    };
+
+    let new_product = Product {
+        quantity,
+        total_dispensed,
+        updated_at: time(),
+        version: product.version + 1,
+        ..product
+    };
+    PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(product_id, new_product.clone()));
+    if movement.reason == MovementReason::Dispense {
+        release_daily_dispense(
+            new_product.warehouse_id,
+            movement.delta.unsigned_abs(),
+            movement.timestamp,
+        );
+    }
+
+    MOVEMENT_STORAGE.with(|movements| {
+        let mut movements = movements.borrow_mut();
+        let id = movements.last_key_value().map_or(0, |(id, _)| id + 1);
+        movements.insert(
+            id,
+            StockMovement {
+                id,
+                product_id,
+                delta: -movement.delta,
+                reason: MovementReason::Reverse,
+                note: None,
+                timestamp: time(),
+                reversed: false,
+                reverses: Some(movement.id),
+            },
+        );
+        movements.insert(
+            movement.id,
+            StockMovement {
+                reversed: true,
+                ..movement
+            },
+        );
+    });
+
+    Ok(new_product)
+}
+
+// get the inbound, outbound and net quantity change of a product since a point in time
+#[ic_cdk::query]
+fn get_quantity_delta(product_id: u64, since_ns: u64) -> Result<QuantityDelta, Error> {
+    get_product_by_id(product_id)?;
+
+    let mut delta = QuantityDelta {
+        product_id,
+        ..Default::default()
+    };
+    for movement in get_product_movements(product_id) {
+        if movement.timestamp < since_ns {
+            continue;
+        }
+        if movement.delta < 0 {
+            delta.outbound = delta.outbound.saturating_add(movement.delta.unsigned_abs());
+        } else {
+            delta.inbound = delta.inbound.saturating_add(movement.delta as u64);
+        }
+        delta.net = delta.net.saturating_add(movement.delta);
+    }
+    Ok(delta)
+}
+
+// get products that have no unit price yet
+#[ic_cdk::query]
+fn get_products_missing_price() -> Result<ProductList, Error> {
+    let products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| !product.archived && product.unit_price == 0)
+            .collect()
+    });
+
+    match products.len() {
+        0 => Err(Error::NotFound {
+            msg: "all products are priced".to_string(),
+        }),
+        _ => Ok(product_list(products)),
+    }
+}
+
+// archive every product that is out of stock, returning the number archived. archived products
+// can still be read by id and are unarchived by the next restock
+#[ic_cdk::update]
+fn archive_out_of_stock() -> Result<u64, Error> {
+    require_authenticated()?;
+    let empty: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.quantity == 0 && !product.archived)
+            .collect()
+    });
+
+    let count = empty.len() as u64;
+    for product in empty {
+        let archived = Product {
+            archived: true,
+            updated_at: time(),
+            version: product.version + 1,
+            ..product
+        };
+        let id = archived.id;
+        PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(id, archived));
+        record_movement(id, 0, MovementReason::Archive, None);
+    }
+    Ok(count)
+}
+
+// get products whose quantity lies in [min, max], lowest quantity first
+#[ic_cdk::query]
+fn get_products_by_quantity_range(min: u32, max: u32) -> Result<ProductList, Error> {
+    if min > max {
+        return Err(Error::InvalidPayload {
+            msg: format!("min: {} is greater than max: {}", min, max),
+        });
+    }
+
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| !product.archived && (min..=max).contains(&product.quantity))
+            .collect()
+    });
+    products.sort_by_key(|product| product.quantity);
+
+    match products.len() {
+        0 => Err(Error::NotFound {
+            msg: format!(
+                "no products with quantity between {} and {} found",
+                min, max
+            ),
+        }),
+        _ => {
+            let list = product_list(products);
+            check_response_size(&list.items)?;
+            Ok(list)
+        }
+    }
+}
+
+// get the most recently changed products, newest first and at most 100. products that
+// haven't changed since they were added are skipped
+#[ic_cdk::query]
+fn get_recently_edited_products(limit: u64) -> Vec<Product> {
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                !product.archived
+                    && product.updated_at != 0
+                    && product.updated_at != product.added_at
+            })
+            .collect()
+    });
+    products.sort_by_key(|product| Reverse(product.updated_at));
+    products.truncate(limit.min(100) as usize);
+    products
+}
+
+// get the products that have not expired yet, soonest expiry first and at most 100.
+// products without an expiry are left out
+#[ic_cdk::query]
+fn get_products_by_expiry(limit: u64) -> Vec<Product> {
+    let now = time();
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                !product.archived && product.expires_at != 0 && product.expires_at > now
+            })
+            .collect()
+    });
+    products.sort_by_key(|product| (product.expires_at, product.id));
+    products.truncate(limit.min(100) as usize);
+    products
+}
+
+// function to spread the quantity of a product evenly over the warehouses holding it, so
+// every warehouse ends up with about the same stock of each lot. units never move from one
+// lot number or expiry date to another, and expired lots are left alone. every new quantity
+// is checked against max_stock, safety_stock and the receiving warehouses before anything
+// is written, so a rebalance that doesn't fit leaves all lots untouched
+#[ic_cdk::update]
+fn rebalance_product(product_name: String, category: String) -> Result<Vec<Product>, Error> {
+    require_authenticated()?;
+    let name = normalize(&product_name).to_lowercase();
+    let category = normalize(&category).to_lowercase();
+    let now = time();
+    let lots: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                !product.archived
+                    && (product.expires_at == 0 || product.expires_at > now)
+                    && product.name.to_lowercase() == name
+                    && product.category.to_lowercase() == category
+            })
+            .collect()
+    });
+    if lots.is_empty() {
+        return Err(Error::NotFound {
+            msg: format!(
+                "no lots of product: {} in category: {} found",
+                product_name, category
+            ),
+        });
+    }
+
+    // lots sharing a lot number and expiry date, by the warehouse holding them
+    let mut groups: BTreeMap<(String, u64), BTreeMap<u64, Vec<Product>>> = BTreeMap::new();
+    for lot in lots {
+        groups
+            .entry((lot.lot_number.clone(), lot.expires_at))
+            .or_default()
+            .entry(lot.warehouse_id)
+            .or_default()
+            .push(lot);
+    }
+
+    // the first warehouses of a group take one extra unit each until the remainder is used up
+    let mut planned: Vec<(Product, u32)> = Vec::new();
+    for holdings in groups.into_values() {
+        let total: u64 = holdings
+            .values()
+            .flatten()
+            .map(|lot| lot.quantity as u64)
+            .sum();
+        let count = holdings.len() as u64;
+        for (index, lots) in holdings.into_values().enumerate() {
+            let share = total / count + u64::from((index as u64) < total % count);
+            planned.extend(plan_warehouse_share(lots, share)?);
+        }
+    }
+    planned.sort_by_key(|(lot, _)| lot.id);
+
+    // net change per warehouse, with the unit volume used for its capacity check
+    let mut changes: BTreeMap<u64, (i64, u32)> = BTreeMap::new();
+    for (lot, share) in &planned {
+        let change = changes
+            .entry(lot.warehouse_id)
+            .or_insert((0, lot.volume_cm3));
+        change.0 += *share as i64 - lot.quantity as i64;
+    }
+    for (warehouse_id, (change, unit_volume)) in changes {
+        if change <= 0 {
+            continue;
+        }
+        let warehouse = get_warehouse_by_id(warehouse_id)?;
+        if !warehouse.is_active {
+            return Err(Error::InvalidPayload {
+                msg: format!("warehouse: {} is not active", warehouse.name),
+            });
+        }
+        check_warehouse_capacity(&warehouse, change as u64, unit_volume)?;
+    }
+
+    let mut rebalanced = Vec::with_capacity(planned.len());
+    for (lot, share) in planned {
+        if share == lot.quantity {
+            rebalanced.push(lot);
+            continue;
+        }
+        let delta = share as i64 - lot.quantity as i64;
+        let new_lot = Product {
+            quantity: share,
+            updated_at: time(),
+            version: lot.version + 1,
+            ..lot
+        };
+        PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(new_lot.id, new_lot.clone()));
+        let reason = if delta < 0 {
+            MovementReason::TransferOut
+        } else {
+            MovementReason::TransferIn
+        };
+        record_movement(new_lot.id, delta, reason, Some("rebalance".to_string()));
+        if delta < 0 {
+            check_stock_alert(&new_lot);
+        }
+        rebalanced.push(new_lot);
+    }
+
+    Ok(rebalanced)
+}
+
+// New quantities for the lots of one lot number in one warehouse, so together they hold
+// share units. units are added to the first lot and taken from the lots in order, each
+// down to its safety stock
+fn plan_warehouse_share(lots: Vec<Product>, share: u64) -> Result<Vec<(Product, u32)>, Error> {
+    let held: u64 = lots.iter().map(|lot| lot.quantity as u64).sum();
+    let mut planned = Vec::with_capacity(lots.len());
+    if share >= held {
+        let mut extra = share - held;
+        for lot in lots {
+            let quantity =
+                u32::try_from(lot.quantity as u64 + extra).map_err(|_| Error::InvalidPayload {
+                    msg: format!(
+                        "lot {} of product: {} can't hold that many",
+                        lot.id, lot.name
+                    ),
+                })?;
+            if lot.max_stock != 0 && quantity > lot.max_stock {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "lot {} of product: {} can hold at most {}",
+                        lot.id, lot.name, lot.max_stock
+                    ),
+                });
+            }
+            extra = 0;
+            planned.push((lot, quantity));
+        }
+    } else {
+        let mut surplus = held - share;
+        for lot in lots {
+            let spare = lot.quantity.saturating_sub(lot.safety_stock) as u64;
+            let taken = spare.min(surplus);
+            surplus -= taken;
+            let quantity = lot.quantity - taken as u32;
+            planned.push((lot, quantity));
+        }
+        if surplus > 0 {
+            let (lot, _) = &planned[0];
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "lot {} of product: {} must keep its safety stock of {}",
+                    lot.id, lot.name, lot.safety_stock
+                ),
+            });
+        }
+    }
+    Ok(planned)
+}
+
+// get groups of products sharing a name, category and warehouse, compared case insensitively.
+// each group holds at least two products and is ordered by id, groups that would take the
+// listing past MAX_RESULTS products are left out
+#[ic_cdk::query]
+fn find_duplicate_products() -> DuplicateGroups {
+    let mut groups: BTreeMap<(String, String, u64), Vec<Product>> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|s| {
+        for (_, product) in s.borrow().iter() {
+            let key = (
+                product.name.to_lowercase(),
+                product.category.to_lowercase(),
+                product.warehouse_id,
+            );
+            groups.entry(key).or_default().push(product);
+        }
+    });
+
+    let mut duplicates = DuplicateGroups::default();
+    let mut listed = 0;
+    for group in groups.into_values().filter(|group| group.len() > 1) {
+        if listed + group.len() > MAX_RESULTS {
+            duplicates.truncated = true;
+            break;
+        }
+        listed += group.len();
+        duplicates.groups.push(group);
+    }
+    duplicates
+}
+
+// get the products added or changed after a point in time, least recently changed first,
+// so a client can keep a local copy in sync without fetching everything
+#[ic_cdk::query]
+fn get_products_changed_since(timestamp_ns: u64) -> ProductList {
+    let changed_at = |product: &Product| product.updated_at.max(product.added_at);
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| changed_at(product) > timestamp_ns)
+            .collect()
+    });
+    products.sort_by_key(|product| (changed_at(product), product.id));
+    product_list(products)
+}
+
+// get the quantity weighted average unit price over all lots of a product, rounded down
+#[ic_cdk::query]
+fn get_weighted_avg_price(product_name: String, category: String) -> Result<u64, Error> {
+    let name = normalize(&product_name).to_lowercase();
+    let category = normalize(&category).to_lowercase();
+    let lots: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.name.to_lowercase() == name && product.category.to_lowercase() == category
+            })
+            .collect()
+    });
+    if lots.is_empty() {
+        return Err(Error::NotFound {
+            msg: format!("no lots of product: {} found", product_name),
+        });
+    }
+
+    let overflow = || Error::InvalidPayload {
+        msg: format!("value of product: {} would overflow", product_name),
+    };
+    let mut total_value: u128 = 0;
+    let mut total_quantity: u128 = 0;
+    for lot in &lots {
+        let value = (lot.quantity as u128)
+            .checked_mul(lot.unit_price as u128)
+            .ok_or_else(overflow)?;
+        total_value = total_value.checked_add(value).ok_or_else(overflow)?;
+        total_quantity += lot.quantity as u128;
+    }
+    if total_quantity == 0 {
+        return Err(Error::InvalidPayload {
+            msg: format!("product: {} has no quantity to average over", product_name),
+        });
+    }
+
+    u64::try_from(total_value / total_quantity).map_err(|_| overflow())
+}
+
+// Widest id window get_products_in_id_range scans in one call
+const MAX_ID_RANGE_SPAN: u64 = MAX_RESULTS as u64;
+
+// get the products whose ids lie in [start_id, end_id] in id order, for walking
+// the catalog in contiguous chunks; an empty window is not an error
+#[ic_cdk::query]
+fn get_products_in_id_range(start_id: u64, end_id: u64) -> Result<Vec<Product>, Error> {
+    if start_id > end_id {
+        return Err(Error::InvalidPayload {
+            msg: format!("start_id: {} is greater than end_id: {}", start_id, end_id),
+        });
+    }
+    if end_id - start_id >= MAX_ID_RANGE_SPAN {
+        return Err(Error::InvalidPayload {
+            msg: format!("id range can span at most {} ids", MAX_ID_RANGE_SPAN),
+        });
+    }
+
+    let products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .range(start_id..=end_id)
+            .map(|(_, product)| product)
+            .collect()
+    });
+    check_response_size(&products)?;
+    Ok(products)
+}
+
+// get the products the caller may see: those in warehouses the caller owns, or every
+// product for controllers. anonymous callers and callers without warehouses get an empty list
+#[ic_cdk::query]
+fn get_visible_products() -> Result<ProductList, Error> {
+    let caller = caller();
+    let products: Vec<Product> = if is_controller(&caller) {
+        PRODUCT_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, product)| product)
+                .filter(|product| !product.archived)
+                .collect()
+        })
+    } else if caller == Principal::anonymous() {
+        // the anonymous principal never owns a warehouse
+        Vec::new()
+    } else {
+        let owned: BTreeSet<u64> = WAREHOUSE_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .filter(|(_, warehouse)| warehouse.owner == caller)
+                .map(|(id, _)| id)
+                .collect()
+        });
+        PRODUCT_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, product)| product)
+                .filter(|product| !product.archived && owned.contains(&product.warehouse_id))
+                .collect()
+        })
+    };
+
+    let list = product_list(products);
+    check_response_size(&list.items)?;
+    Ok(list)
+}
+
+// Query function to get all warehouses
+#[ic_cdk::query]
+fn get_all_warehouses() -> Result<WarehouseList, Error> {
+    // Retrieve all Warehouses from the storage
+    let warehousemap: Vec<(u64, Warehouse)> =
+        WAREHOUSE_STORAGE.with(|s| s.borrow().iter().collect());
+    // Extract the Warehouses from the tuple and create a vector
+    let warehouses: Vec<Warehouse> = warehousemap
+        .into_iter()
+        .map(|(_, warehouse)| warehouse)
+        .collect();
+
+    match warehouses.len() {
+        0 => Err(Error::NotFound {
+            msg: "no Warehouses found".to_string(),
+        }),
+        _ => {
+            let list = warehouse_list(warehouses);
+            check_response_size(&list.items)?;
+            Ok(list)
+        }
+    }
+}
+
+// Great-circle distance in km between two (latitude, longitude) points
+fn distance_km((lat_a, lon_a): (f64, f64), (lat_b, lon_b): (f64, f64)) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let d_lat = (lat_b - lat_a).to_radians();
+    let d_lon = (lon_b - lon_a).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2)
+        + lat_a.to_radians().cos() * lat_b.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+// estimate the cost in minor currency units of moving an amount of a product to another
+// warehouse, distance in km times weight in kg times the rate
+#[ic_cdk::query]
+fn estimate_transfer_cost(
+    product_id: u64,
+    amount: u32,
+    target_warehouse_id: u64,
+    rate_per_km_per_kg: u64,
+) -> Result<u64, Error> {
+    let product = get_product_by_id(product_id)?;
+    let source = get_warehouse_by_id(product.warehouse_id)?;
+    let target = get_warehouse_by_id(target_warehouse_id)?;
+
+    if product.weight_grams == 0 {
+        return Err(Error::InvalidPayload {
+            msg: format!("product: {} has no weight", product.name),
+        });
+    }
+    let (from, to) = match (source.coordinates, target.coordinates) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: "both warehouses need coordinates".to_string(),
+            })
+        }
+    };
+
+    let weight_kg = amount as f64 * product.weight_grams as f64 / 1000.0;
+    let cost = distance_km(from, to) * weight_kg * rate_per_km_per_kg as f64;
+    if cost >= u64::MAX as f64 {
+        return Err(Error::InvalidPayload {
+            msg: "estimated cost is too large".to_string(),
+        });
+    }
+    Ok(cost.round() as u64)
+}
+
+// get the warehouses open at a minute of a weekday, those without opening hours are always open
+#[ic_cdk::query]
+fn get_open_warehouses(weekday: u8, minute_of_day: u32) -> WarehouseList {
+    let warehouses = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, warehouse)| warehouse)
+            .filter(|warehouse| {
+                warehouse.opening_hours.is_empty()
+                    || warehouse.opening_hours.iter().any(|&(day, open, close)| {
+                        day == weekday && (open..close).contains(&minute_of_day)
+                    })
+            })
+            .collect()
+    });
+    warehouse_list(warehouses)
+}
+
+// get the warehouses carrying a tag, the tag is matched case-insensitively
+#[ic_cdk::query]
+fn get_warehouses_by_tag(tag: String) -> Result<WarehouseList, Error> {
+    let tag = normalize(&tag).to_lowercase();
+    if tag.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "tag can't be empty".to_string(),
+        });
+    }
+
+    let warehouses: Vec<Warehouse> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, warehouse)| warehouse)
+            .filter(|warehouse| warehouse.tags.contains(&tag))
+            .collect()
+    });
+
+    match warehouses.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("no warehouses tagged: {} found", tag),
+        }),
+        _ => Ok(warehouse_list(warehouses)),
+    }
+}
+
+// get the warehouses created within [start_ns, end_ns], newest first
+#[ic_cdk::query]
+fn get_warehouses_created_between(start_ns: u64, end_ns: u64) -> Result<WarehouseList, Error> {
+    if start_ns > end_ns {
+        return Err(Error::InvalidPayload {
+            msg: format!("start: {} is after end: {}", start_ns, end_ns),
+        });
+    }
+
+    let mut warehouses: Vec<Warehouse> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, warehouse)| warehouse)
+            .filter(|warehouse| (start_ns..=end_ns).contains(&warehouse.created_at))
+            .collect()
+    });
+    warehouses.sort_by_key(|warehouse| Reverse(warehouse.created_at));
+    Ok(warehouse_list(warehouses))
+}
+
+// Utilization of a warehouse, None when its capacity is unlimited
+fn warehouse_utilization(warehouse: &Warehouse) -> Option<WarehouseUtilization> {
+    let (used, capacity) = match warehouse.capacity_mode {
+        CapacityMode::Units => (
+            warehouse_used_quantity(warehouse.id),
+            warehouse.capacity as u64,
+        ),
+        CapacityMode::Volume => (
+            warehouse_used_volume(warehouse.id),
+            warehouse.volume_capacity_cm3,
+        ),
+    };
+    if capacity == 0 {
+        return None;
+    }
+    Some(WarehouseUtilization {
+        warehouse_id: warehouse.id,
+        name: warehouse.name.clone(),
+        used,
+        capacity,
+        utilization_pct: (used as u128 * 100 / capacity as u128) as u64,
+        overflow: used.saturating_sub(capacity),
+    })
+}
+
+// get the warehouses holding more than their capacity, largest overflow first. this can
+// happen when capacity is lowered after stock was added or stock arrives through an import
+#[ic_cdk::query]
+fn get_overcapacity_warehouses() -> Vec<WarehouseUtilization> {
+    let mut overfull: Vec<WarehouseUtilization> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter_map(|(_, warehouse)| warehouse_utilization(&warehouse))
+            .filter(|utilization| utilization.overflow > 0)
+            .collect()
+    });
+    overfull.sort_by_key(|utilization| Reverse(utilization.overflow));
+    overfull
+}
+
+// get the warehouses filled to at least threshold_pct of their capacity, fullest first.
+// warehouses with unlimited capacity are never included
+#[ic_cdk::query]
+fn get_capacity_alerts(threshold_pct: u8) -> Result<Vec<WarehouseUtilization>, Error> {
+    if threshold_pct > 100 {
+        return Err(Error::InvalidPayload {
+            msg: format!("threshold_pct: {} is above 100", threshold_pct),
+        });
+    }
+
+    let mut alerts: Vec<WarehouseUtilization> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter_map(|(_, warehouse)| warehouse_utilization(&warehouse))
+            .filter(|utilization| utilization.utilization_pct >= threshold_pct as u64)
+            .collect()
+    });
+    alerts.sort_by_key(|utilization| Reverse(utilization.utilization_pct));
+    Ok(alerts)
+}
+
+// get the active warehouses that accept a category and have room for the required units,
+// most free units first. warehouses with unlimited capacity come before all others, while
+// warehouses counting capacity by volume are left out as units alone don't tell the space needed
+#[ic_cdk::query]
+fn get_placement_candidates(category: String, required: u32) -> Result<WarehouseList, Error> {
+    let category = normalize(&category);
+    let mut candidates: Vec<(Warehouse, u64)> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, warehouse)| warehouse)
+            .filter(|warehouse| {
+                warehouse.capacity_mode == CapacityMode::Units
+                    && check_warehouse_accepts(warehouse, &category, required as u64, 0).is_ok()
+            })
+            .map(|warehouse| {
+                let free = match warehouse.capacity {
+                    0 => u64::MAX,
+                    capacity => {
+                        (capacity as u64).saturating_sub(warehouse_used_quantity(warehouse.id))
+                    }
+                };
+                (warehouse, free)
+            })
+            .collect()
+    });
+    candidates.sort_by_key(|(warehouse, free)| (Reverse(*free), warehouse.id));
+
+    match candidates.len() {
+        0 => Err(Error::NotFound {
+            msg: format!(
+                "no warehouse can take {} units of category: {}",
+                required, category
+            ),
+        }),
+        _ => Ok(warehouse_list(
+            candidates
+                .into_iter()
+                .map(|(warehouse, _)| warehouse)
+                .collect(),
+        )),
+    }
+}
+
+// get the ids of all warehouses in ascending order
+#[ic_cdk::query]
+fn get_warehouse_ids() -> Vec<u64> {
+    WAREHOUSE_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect())
+}
+
+// Get Warehouses by city and name content
+#[ic_cdk::query]
+fn get_warehouse_by_name(search: String) -> Result<WarehouseList, Error> {
+    let query = search.to_lowercase();
+    // Retrieve all Warehouses from the storage
+    let warehouse_map: Vec<(u64, Warehouse)> =
+        WAREHOUSE_STORAGE.with(|s| s.borrow().iter().collect());
+    let warehouses: Vec<Warehouse> = warehouse_map
+        .into_iter()
+        .map(|(_, warehouse)| warehouse)
+        .collect();
+
+    // Filter the warehouses by name
+    let incomplete_products: Vec<Warehouse> = warehouses
+        .into_iter()
+        .filter(|warehouse| (warehouse.name).to_lowercase().contains(&query))
+        .collect();
+
+    // Check if any warehouses are found
+    match incomplete_products.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("No warehouses for name: {} could be found", query),
+        }),
+        _ => Ok(warehouse_list(incomplete_products)),
+    }
+}
+
+// get warehouse by ID
+#[ic_cdk::query]
+fn get_warehouse_by_id(id: u64) -> Result<Warehouse, Error> {
+    match WAREHOUSE_STORAGE.with(|warehouses| warehouses.borrow().get(&id)) {
+        Some(warehouse) => Ok(warehouse),
+        None => Err(Error::NotFound {
+            msg: format!("warehouse of id: {} not found", id),
+        }),
+    }
+}
+
+// get a warehouse with its product count and total quantity, without the products themselves
+#[ic_cdk::query]
+fn get_warehouse_summary(id: u64) -> Result<WarehouseSummary, Error> {
+    let warehouse = get_warehouse_by_id(id)?;
+
+    let (product_count, total_quantity) = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, product)| product.warehouse_id == id)
+            .fold((0u64, 0u64), |(count, total), (_, product)| {
+                (count + 1, total + product.quantity as u64)
+            })
+    });
+
+    Ok(WarehouseSummary {
+        warehouse,
+        product_count,
+        total_quantity,
+    })
+}
+
+// Build a new warehouse record from a validated payload
+fn warehouse_from_payload(id: u64, payload: WarehousePayload) -> Warehouse {
+    Warehouse {
+        schema_version: WAREHOUSE_SCHEMA_VERSION,
+        id,
+        display_id: display_id(&config().warehouse_id_prefix, id),
+        name: payload.name,
+        address: payload.address,
+        city: payload.city,
+        owner: caller(),
+        capacity: payload.capacity,
+        capacity_mode: payload.capacity_mode,
+        volume_capacity_cm3: payload.volume_capacity_cm3,
+        is_active: true,
+        allowed_categories: payload.allowed_categories,
+        min_initial_stock: payload.min_initial_stock,
+        max_distinct_categories: payload.max_distinct_categories,
+        contact_email: payload.contact_email,
+        contact_phone: payload.contact_phone,
+        opening_hours: payload.opening_hours,
+        coordinates: payload.coordinates,
+        tags: payload.tags,
+        daily_dispense_limit: payload.daily_dispense_limit,
+        created_at: time(),
+        updated_at: time(),
+    }
+}
+
+// Create new Warehouse
+#[ic_cdk::update]
+fn add_warehouse(payload: WarehousePayload) -> Result<Warehouse, Error> {
+    require_authenticated()?;
+    let payload = payload.normalized();
+    // validate payload
+    if let Err(errors) = payload.validate() {
+        return Err(Error::InvalidPayload {
+            msg: errors.to_string(),
+        });
+    }
+    validate_name(&payload.name)?;
+
+    // the id is only taken once the warehouse is known to fit
+    let id = peek_next_id();
+    let warehouse = warehouse_from_payload(id, payload.clone());
+    check_stored_size(&warehouse, "warehouse")?;
+    next_id()?;
+
+    match WAREHOUSE_STORAGE.with(|s| s.borrow_mut().insert(id, warehouse.clone())) {
+        Some(_) => Err(Error::InvalidPayload {
+            msg: format!("Could not add warehouse name: {}", payload.name),
+        }),
+        None => Ok(warehouse),
+    }
+}
+
+// update function for the owner or a controller to edit a warehouse
+#[ic_cdk::update]
+fn edit_warehouse(payload: EditWarehousePayload) -> Result<Warehouse, Error> {
+    require_authenticated()?;
+    if let Err(errors) = payload.validate() {
+        return Err(Error::InvalidPayload {
+            msg: errors.to_string(),
+        });
+    }
+    let name = normalize(&payload.name);
+    validate_name(&name)?;
+
+    let warehouse =
+        WAREHOUSE_STORAGE.with(|warehouses| warehouses.borrow().get(&payload.warehouse_id));
+
+    match warehouse {
+        Some(warehouse) => {
+            require_warehouse_owner(&warehouse)?;
+            let new_warehouse = Warehouse {
+                name,
+                capacity: payload.capacity.unwrap_or(warehouse.capacity),
+                allowed_categories: payload
+                    .allowed_categories
+                    .map_or(warehouse.allowed_categories.clone(), |categories| {
+                        categories.iter().map(|c| normalize(c)).collect()
+                    }),
+                min_initial_stock: payload
+                    .min_initial_stock
+                    .unwrap_or(warehouse.min_initial_stock),
+                max_distinct_categories: payload
+                    .max_distinct_categories
+                    .unwrap_or(warehouse.max_distinct_categories),
+                contact_email: payload
+                    .contact_email
+                    .map_or(warehouse.contact_email.clone(), |email| {
+                        email.trim().to_string()
+                    }),
+                contact_phone: payload
+                    .contact_phone
+                    .map_or(warehouse.contact_phone.clone(), |phone| normalize(&phone)),
+                opening_hours: payload
+                    .opening_hours
+                    .unwrap_or(warehouse.opening_hours.clone()),
+                coordinates: payload.coordinates.or(warehouse.coordinates),
+                daily_dispense_limit: payload
+                    .daily_dispense_limit
+                    .unwrap_or(warehouse.daily_dispense_limit),
+                updated_at: time(),
+                ..warehouse.clone()
+            };
+            check_stored_size(&new_warehouse, "warehouse")?;
+
+            match WAREHOUSE_STORAGE
+                .with(|s| s.borrow_mut().insert(warehouse.id, new_warehouse.clone()))
+            {
+                Some(_) => Ok(new_warehouse),
+                None => Err(Error::InvalidPayload {
+                    msg: format!("Could not edit warehouse     title: {}", warehouse.name),
+                }),
+            }
+        }
+        None => Err(Error::NotFound {
+            msg: format!("warehouse of id: {} not found", payload.warehouse_id),
+        }),
+    }
+}
+
+// get the distinct categories of the products held in a warehouse, sorted alphabetically.
+// categories are compared case insensitively like the warehouse's category limit does, each
+// is returned as spelled by the first product holding it
+#[ic_cdk::query]
+fn get_warehouse_categories(warehouse_id: u64) -> Result<Vec<String>, Error> {
+    get_warehouse_by_id(warehouse_id)?;
+
+    let mut categories: BTreeMap<String, String> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|s| {
+        for (_, product) in s.borrow().iter() {
+            if product.warehouse_id == warehouse_id {
+                categories
+                    .entry(product.category.to_lowercase())
+                    .or_insert(product.category);
+            }
+        }
+    });
+
+    Ok(categories.into_values().collect())
+}
+
+// Create several warehouses at once, nothing is added unless every payload is valid
+// and no name is duplicated within the batch or among existing warehouses
+#[ic_cdk::update]
+fn add_warehouses_batch(payloads: Vec<WarehousePayload>) -> Result<Vec<Warehouse>, Error> {
+    require_authenticated()?;
+    let payloads: Vec<WarehousePayload> = payloads
+        .into_iter()
+        .map(WarehousePayload::normalized)
+        .collect();
+    let mut names: BTreeSet<String> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, warehouse)| warehouse.name.to_lowercase())
+            .collect()
+    });
+
+    for payload in &payloads {
+        if let Err(errors) = payload.validate() {
+            return Err(Error::InvalidPayload {
+                msg: format!("warehouse {}: {}", payload.name, errors),
+            });
+        }
+        validate_name(&payload.name)?;
+        if !names.insert(payload.name.to_lowercase()) {
+            return Err(Error::InvalidPayload {
+                msg: format!("warehouse name: {} already exists", payload.name),
+            });
+        }
+    }
+
+    // ids are only taken once every warehouse is known to fit
+    let first_id = peek_next_id();
+    let mut warehouses = Vec::with_capacity(payloads.len());
+    for (index, payload) in payloads.into_iter().enumerate() {
+        let warehouse = warehouse_from_payload(first_id + index as u64, payload);
+        check_stored_size(&warehouse, "warehouse")?;
+        warehouses.push(warehouse);
+    }
+    for warehouse in &warehouses {
+        next_id()?;
+        WAREHOUSE_STORAGE.with(|s| s.borrow_mut().insert(warehouse.id, warehouse.clone()));
+    }
+
+    Ok(warehouses)
+}
+
+// update function for the owner to move a warehouse to a new address and city
+#[ic_cdk::update]
+fn relocate_warehouse(
+    warehouse_id: u64,
+    new_address: String,
+    new_city: String,
+) -> Result<Warehouse, Error> {
+    require_authenticated()?;
+    let warehouse = get_warehouse_by_id(warehouse_id)?;
+    require_warehouse_owner(&warehouse)?;
+
+    let new_address = normalize(&new_address);
+    let new_city = normalize(&new_city);
+    if new_address.len() < 3 {
+        return Err(Error::InvalidPayload {
+            msg: "address must be at least 3 characters long".to_string(),
+        });
+    }
+    if let Err(err) = validate_address(&new_address).and(validate_city(&new_city)) {
+        return Err(Error::InvalidPayload {
+            msg: err.to_string(),
+        });
+    }
+
+    let new_warehouse = Warehouse {
+        address: new_address,
+        city: new_city,
+        updated_at: time(),
+        ..warehouse
+    };
+    check_stored_size(&new_warehouse, "warehouse")?;
+    WAREHOUSE_STORAGE.with(|s| s.borrow_mut().insert(warehouse_id, new_warehouse.clone()));
+
+    Ok(new_warehouse)
+}
+
+// update function for the owner or a controller to hand a warehouse over to a new owner,
+// the change is kept in the ownership history
+#[ic_cdk::update]
+fn transfer_warehouse_ownership(
+    warehouse_id: u64,
+    new_owner: Principal,
+) -> Result<Warehouse, Error> {
+    require_authenticated()?;
+    let warehouse = get_warehouse_by_id(warehouse_id)?;
+    require_warehouse_owner(&warehouse)?;
+    if new_owner == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "the anonymous principal can't own a warehouse".to_string(),
+        });
+    }
+
+    let new_warehouse = Warehouse {
+        owner: new_owner,
+        updated_at: time(),
+        ..warehouse.clone()
+    };
+    WAREHOUSE_STORAGE.with(|s| s.borrow_mut().insert(warehouse_id, new_warehouse.clone()));
+
+    OWNERSHIP_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let key = history.last_key_value().map_or(0, |(key, _)| key + 1);
+        history.insert(
+            key,
+            OwnershipChange {
+                warehouse_id,
+                previous_owner: warehouse.owner,
+                new_owner,
+                changed_by: caller(),
+                changed_at: time(),
+            },
+        );
+    });
+
+    Ok(new_warehouse)
+}
+
+// get the ownership changes of a warehouse, oldest first
+#[ic_cdk::query]
+fn get_ownership_history(warehouse_id: u64) -> Vec<OwnershipChange> {
+    OWNERSHIP_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .map(|(_, change)| change)
+            .filter(|change| change.warehouse_id == warehouse_id)
+            .collect()
+    })
+}
+
+// get every warehouse with the number of products it holds, sorted by that count
+#[ic_cdk::query]
+fn get_warehouses_by_product_count(descending: bool) -> Vec<(Warehouse, u64)> {
+    let counts = product_counts_by_warehouse();
+
+    let mut warehouses: Vec<(Warehouse, u64)> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(id, warehouse)| (warehouse, counts.get(&id).copied().unwrap_or(0)))
+            .collect()
+    });
+
+    // the sort is stable so equal counts stay ordered by id
+    if descending {
+        warehouses.sort_by_key(|(_, count)| Reverse(*count));
+    } else {
+        warehouses.sort_by_key(|(_, count)| *count);
+    }
+    warehouses
+}
+
+// update function for the owner to activate or deactivate a warehouse
+#[ic_cdk::update]
+fn set_warehouse_active(warehouse_id: u64, is_active: bool) -> Result<Warehouse, Error> {
+    require_authenticated()?;
+    let warehouse = get_warehouse_by_id(warehouse_id)?;
+    require_warehouse_owner(&warehouse)?;
+
+    let new_warehouse = Warehouse {
+        is_active,
+        updated_at: time(),
+        ..warehouse
+    };
+    WAREHOUSE_STORAGE.with(|s| s.borrow_mut().insert(warehouse_id, new_warehouse.clone()));
+    Ok(new_warehouse)
+}
+
+// Store a warehouse with a new set of tags, for its owner or a controller
+fn set_warehouse_tags(warehouse_id: u64, tags: Vec<String>) -> Result<Warehouse, Error> {
+    let warehouse = get_warehouse_by_id(warehouse_id)?;
+    require_warehouse_owner(&warehouse)?;
+
+    let tags = normalize_tags(&tags);
+    if let Err(err) = validate_tags(&tags) {
+        return Err(Error::InvalidPayload {
+            msg: err.to_string(),
+        });
+    }
+
+    let new_warehouse = Warehouse {
+        tags,
+        updated_at: time(),
+        ..warehouse
+    };
+    check_stored_size(&new_warehouse, "warehouse")?;
+    WAREHOUSE_STORAGE.with(|s| s.borrow_mut().insert(warehouse_id, new_warehouse.clone()));
+    Ok(new_warehouse)
+}
+
+// update function for the owner to tag a warehouse, tagging it twice has no effect
+#[ic_cdk::update]
+fn add_warehouse_tag(warehouse_id: u64, tag: String) -> Result<Warehouse, Error> {
+    require_authenticated()?;
+    if normalize(&tag).is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "tag can't be empty".to_string(),
+        });
+    }
+    let mut tags = get_warehouse_by_id(warehouse_id)?.tags;
+    tags.push(tag);
+    set_warehouse_tags(warehouse_id, tags)
+}
+
+// update function for the owner to remove a tag from a warehouse
+#[ic_cdk::update]
+fn remove_warehouse_tag(warehouse_id: u64, tag: String) -> Result<Warehouse, Error> {
+    require_authenticated()?;
+    let tag = normalize(&tag).to_lowercase();
+    let warehouse = get_warehouse_by_id(warehouse_id)?;
+    if !warehouse.tags.contains(&tag) {
+        return Err(Error::NotFound {
+            msg: format!("warehouse: {} isn't tagged: {}", warehouse.name, tag),
+        });
+    }
+    let tags = warehouse.tags.into_iter().filter(|t| *t != tag).collect();
+    set_warehouse_tags(warehouse_id, tags)
+}
+
+// get (warehouse id, warehouse name, product count) for every warehouse, sorted by id
+#[ic_cdk::query]
+fn get_product_counts_per_warehouse() -> Vec<(u64, String, u64)> {
+    let counts = product_counts_by_warehouse();
+
+    WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(id, warehouse)| (id, warehouse.name, counts.get(&id).copied().unwrap_or(0)))
+            .collect()
+    })
+}
+
+// get several warehouses at once, each id resolves to its warehouse or comes back as the missing id.
+// only the first 200 ids are looked up
+#[ic_cdk::query]
+fn get_warehouses_by_ids(ids: Vec<u64>) -> Vec<Result<Warehouse, u64>> {
+    WAREHOUSE_STORAGE.with(|s| {
+        let warehouses = s.borrow();
+        ids.into_iter()
+            .take(200)
+            .map(|id| warehouses.get(&id).ok_or(id))
+            .collect()
+    })
+}
+
+// Most warehouses that can be combined in one get_products_for_warehouses call
+const MAX_WAREHOUSES_PER_QUERY: usize = 50;
+
+// get the products held in any of the given warehouses, ordered by warehouse id then product id.
+// every id must belong to an existing warehouse
+#[ic_cdk::query]
+fn get_products_for_warehouses(warehouse_ids: Vec<u64>) -> Result<ProductList, Error> {
+    if warehouse_ids.len() > MAX_WAREHOUSES_PER_QUERY {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "at most {} warehouses can be queried at once",
+                MAX_WAREHOUSES_PER_QUERY
+            ),
+        });
+    }
+    for id in &warehouse_ids {
+        get_warehouse_by_id(*id)?;
+    }
+
+    let ids: BTreeSet<u64> = warehouse_ids.into_iter().collect();
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| !product.archived && ids.contains(&product.warehouse_id))
+            .collect()
+    });
+    products.sort_by_key(|product| (product.warehouse_id, product.id));
+
+    match products.len() {
+        0 => Err(Error::NotFound {
+            msg: "no products found in the given warehouses".to_string(),
+        }),
+        _ => {
+            let list = product_list(products);
+            check_response_size(&list.items)?;
+            Ok(list)
+        }
+    }
+}
+
+// get a warehouse and its products as a JSON string for external tools, shaped as
+// {"warehouse": {..}, "products": [{..}, ..]} with the same field names as the candid records
+#[ic_cdk::query]
+fn export_warehouse_json(warehouse_id: u64) -> Result<String, Error> {
+    #[derive(Serialize)]
+    struct WarehouseExport {
+        warehouse: Warehouse,
+        products: Vec<Product>,
+    }
+
+    let warehouse = get_warehouse_by_id(warehouse_id)?;
+    let products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.warehouse_id == warehouse_id)
+            .collect()
+    });
+    check_response_size(&products)?;
+
+    serde_json::to_string(&WarehouseExport {
+        warehouse,
+        products,
+    })
+    .map_err(|err| Error::InvalidPayload {
+        msg: format!("could not export warehouse: {}", err),
+    })
+}
+
+// Most items of each kind a global search returns
+const MAX_GLOBAL_SEARCH_RESULTS: usize = 50;
+
+// search products by name or category and warehouses by name or city in one call,
+// at most 50 of each in id order
+#[ic_cdk::query]
+fn global_search(query: String) -> GlobalSearchResult {
+    let query = normalize(&query).to_lowercase();
+
+    let products = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                !product.archived
+                    && (product.name.to_lowercase().contains(&query)
+                        || product.category.to_lowercase().contains(&query))
+            })
+            .take(MAX_GLOBAL_SEARCH_RESULTS)
+            .collect()
+    });
+    let warehouses = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, warehouse)| warehouse)
+            .filter(|warehouse| {
+                warehouse.name.to_lowercase().contains(&query)
+                    || warehouse.city.to_lowercase().contains(&query)
+            })
+            .take(MAX_GLOBAL_SEARCH_RESULTS)
+            .collect()
+    });
+
+    GlobalSearchResult {
+        products,
+        warehouses,
+    }
+}
+
+// Mutations are never accepted from the anonymous principal
+fn require_authenticated() -> Result<Principal, Error> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "anonymous callers cannot call this function".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+// Only controllers of the canister may call admin functions
+fn require_controller() -> Result<(), Error> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "only controllers can call this function".to_string(),
+        })
+    }
+}
+
+// Only the owner of a warehouse or a controller may change it
+fn require_warehouse_owner(warehouse: &Warehouse) -> Result<(), Error> {
+    let caller = caller();
+    if caller == warehouse.owner || is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("only the owner can change warehouse: {}", warehouse.name),
+        })
+    }
+}
+
+// Rewrite every warehouse stored with an older schema version in the current shape,
+// giving those from before display ids existed one, returning the number of migrated
+// warehouses
+fn migrate_stored_warehouses() -> u64 {
+    let outdated: Vec<(u64, Warehouse)> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, warehouse)| warehouse.schema_version < WAREHOUSE_SCHEMA_VERSION)
+            .collect()
+    });
+
+    let prefix = config().warehouse_id_prefix;
+    let count = outdated.len() as u64;
+    for (key, warehouse) in outdated {
+        let mut migrated = Warehouse {
+            schema_version: WAREHOUSE_SCHEMA_VERSION,
+            id: key,
+            ..warehouse
+        };
+        if migrated.display_id.is_empty() {
+            migrated.display_id = display_id(&prefix, key);
+        }
+        WAREHOUSE_STORAGE.with(|s| s.borrow_mut().insert(key, migrated));
+    }
+    count
+}
+
+// Rewrite every product stored with an older schema version in the current shape,
+// returning the number of migrated products
+fn migrate_stored_products() -> u64 {
+    let outdated: Vec<(u64, Product)> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, product)| product.schema_version < PRODUCT_SCHEMA_VERSION)
+            .collect()
+    });
+
+    let prefix = config().product_id_prefix;
+    let count = outdated.len() as u64;
+    for (key, product) in outdated {
+        // fields added since the record's version were filled with defaults on decode,
+        // the record stays under the key it was stored at
+        let mut migrated = Product {
+            schema_version: PRODUCT_SCHEMA_VERSION,
+            id: key,
+            ..product
+        };
+        if migrated.display_id.is_empty() {
+            migrated.display_id = display_id(&prefix, key);
+        }
+        PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(key, migrated));
+    }
+    count
+}
+
+// admin function to migrate stored products to the current schema version
+#[ic_cdk::update]
+fn migrate_products() -> Result<u64, Error> {
+    require_authenticated()?;
+    require_controller()?;
+    Ok(migrate_stored_products())
+}
+
+// store the initial config, defaults are used when none is given
+#[ic_cdk::init]
+fn init(config: Option<Config>) {
+    if let Some(config) = config {
+        if config.currency.code.len() != 3 {
+            ic_cdk::trap("currency code must be a 3 letter ISO 4217 code");
+        }
+        if config.min_name_length == 0 {
+            ic_cdk::trap("min_name_length must be at least 1");
+        }
+        if config.product_id_prefix.len() > MAX_ID_PREFIX_BYTES
+            || config.warehouse_id_prefix.len() > MAX_ID_PREFIX_BYTES
+        {
+            ic_cdk::trap("display id prefixes must be at most 8 bytes");
+        }
+        CONFIG
+            .with(|c| c.borrow_mut().set(config))
+            .expect("Cannot store the config");
+    }
+    schedule_cycle_sampling(crate::config().track_cycles);
+}
+
+// migrate stored warehouses and products after every upgrade
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_stored_warehouses();
+    migrate_stored_products();
+    schedule_cycle_sampling(config().track_cycles);
+}
+
+// get products whose warehouse no longer exists in storage
+#[ic_cdk::query]
+fn find_orphaned_products() -> ProductList {
+    product_list(orphaned_products())
+}
+
+// Products whose warehouse no longer exists in storage
+fn orphaned_products() -> Vec<Product> {
+    let products: Vec<Product> =
+        PRODUCT_STORAGE.with(|s| s.borrow().iter().map(|(_, product)| product).collect());
+
+    WAREHOUSE_STORAGE.with(|warehouses| {
+        let warehouses = warehouses.borrow();
+        products
+            .into_iter()
+            .filter(|product| !warehouses.contains_key(&product.warehouse_id))
+            .collect()
+    })
+}
+
+// Most ids delete_products_batch takes in one call
+const MAX_DELETE_BATCH: usize = 200;
+
+// admin function to delete several products, each id comes back as Ok when it was deleted
+// or as Err when no such product exists. the stock a deleted product held is written off
+// in the ledger
+#[ic_cdk::update]
+fn delete_products_batch(ids: Vec<u64>) -> Result<Vec<Result<u64, u64>>, Error> {
+    require_authenticated()?;
+    require_controller()?;
+    if ids.len() > MAX_DELETE_BATCH {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "at most {} products can be deleted at once",
+                MAX_DELETE_BATCH
+            ),
+        });
+    }
+
+    Ok(ids
+        .into_iter()
+        .map(
+            |id| match PRODUCT_STORAGE.with(|s| s.borrow_mut().remove(&id)) {
+                Some(product) => {
+                    record_movement(id, -(product.quantity as i64), MovementReason::Delete, None);
+                    Ok(id)
+                }
+                None => Err(id),
+            },
+        )
+        .collect())
+}
+
+// admin function to set every product's reorder level to its average daily consumption
+// over its lifetime times the lead time, never below 1. dispensed and ordered units that
+// weren't undone count as consumption. returns the number of products whose level changed
+#[ic_cdk::update]
+fn recompute_reorder_levels(lead_time_days: u32) -> Result<u64, Error> {
+    require_authenticated()?;
+    require_controller()?;
+    if lead_time_days == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "lead_time_days must be greater than 0".to_string(),
+        });
+    }
+
+    const NS_PER_DAY: u64 = 86_400_000_000_000;
+    let mut consumed: BTreeMap<u64, u64> = BTreeMap::new();
+    MOVEMENT_STORAGE.with(|movements| {
+        for (_, movement) in movements.borrow().iter() {
+            if movement.delta < 0
+                && !movement.reversed
+                && movement.reason == MovementReason::Dispense
+            {
+                *consumed.entry(movement.product_id).or_insert(0) += movement.delta.unsigned_abs();
+            }
+        }
+    });
+
+    let now = time();
+    let products: Vec<Product> =
+        PRODUCT_STORAGE.with(|s| s.borrow().iter().map(|(_, product)| product).collect());
+    let mut updated = 0;
+    for product in products {
+        let days = (now.saturating_sub(product.added_at) / NS_PER_DAY).max(1);
+        let total = consumed.get(&product.id).copied().unwrap_or(0) as u128;
+        let level = (total * lead_time_days as u128)
+            .div_ceil(days as u128)
+            .clamp(1, u32::MAX as u128) as u32;
+        if level == product.reorder_level {
+            continue;
+        }
+        let new_product = Product {
+            reorder_level: level,
+            updated_at: now,
+            version: product.version + 1,
+            ..product
+        };
+        PRODUCT_STORAGE.with(|s| s.borrow_mut().insert(new_product.id, new_product));
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+// admin function to set the reorder level new products of a category get when they don't
+// provide one, products that set their own level keep it
+#[ic_cdk::update]
+fn set_category_reorder_default(category: String, level: u32) -> Result<(), Error> {
+    require_authenticated()?;
+    require_controller()?;
+    // categories are normalized and bounded the same way add_product does
+    let category = normalize(&category);
+    if category.is_empty() || category.len() > MAX_LABEL_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("category must be between 1 and {} bytes", MAX_LABEL_BYTES),
+        });
+    }
+
+    CATEGORY_REORDER_DEFAULTS.with(|defaults| {
+        defaults.borrow_mut().insert(
+            CategoryKey::new(&category),
+            CategoryReorderDefault {
+                category,
+                reorder_level: level,
+            },
+        )
+    });
+    Ok(())
+}
+
+// get the per category default reorder levels, sorted by category
+#[ic_cdk::query]
+fn get_category_reorder_defaults() -> Vec<CategoryReorderDefault> {
+    let mut defaults: Vec<CategoryReorderDefault> = CATEGORY_REORDER_DEFAULTS.with(|defaults| {
+        defaults
+            .borrow()
+            .iter()
+            .map(|(_, default)| default)
+            .collect()
+    });
+    defaults.sort_by_key(|default| default.category.to_lowercase());
+    defaults
+}
+
+// admin function to report products whose warehouse is missing from storage
+#[ic_cdk::query]
+fn verify_integrity() -> Result<Vec<IntegrityIssue>, Error> {
+    require_controller()?;
+
+    Ok(WAREHOUSE_STORAGE.with(|warehouses| {
+        let warehouses = warehouses.borrow();
+        PRODUCT_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, product)| product)
+                .filter(|product| !warehouses.contains_key(&product.warehouse_id))
+                .map(|product| IntegrityIssue {
+                    product_id: product.id,
+                    warehouse_id: product.warehouse_id,
+                    issue: "warehouse does not exist".to_string(),
+                })
+                .collect()
+        })
+    }))
+}
+
+// admin function to move every orphaned product into an existing warehouse
+#[ic_cdk::update]
+fn reassign_orphaned_products(target_warehouse_id: u64) -> Result<Vec<Product>, Error> {
+    require_authenticated()?;
+    require_controller()?;
+    let warehouse = get_warehouse_by_id(target_warehouse_id)?;
+
+    let reassigned: Vec<Product> = orphaned_products()
+        .into_iter()
+        .map(|product| Product {
+            warehouse_id: warehouse.id,
+            updated_at: time(),
+            version: product.version + 1,
+            ..product
+        })
+        .collect();
+
+    PRODUCT_STORAGE.with(|s| {
+        let mut products = s.borrow_mut();
+        for product in &reassigned {
+            products.insert(product.id, product.clone());
+        }
+    });
+
+    Ok(reassigned)
+}
+
+// Current stored config
+fn config() -> Config {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// Longest display id prefix, in bytes
+const MAX_ID_PREFIX_BYTES: usize = 8;
+
+// Human readable id combining a prefix with the numeric id, e.g. "PRD-000123"
+fn display_id(prefix: &str, id: u64) -> String {
+    format!("{}-{:06}", prefix, id)
+}
+
+// Apply a change to the stored config
+fn update_config(change: impl FnOnce(&mut Config)) {
+    CONFIG.with(|config| {
+        let mut value = config.borrow().get().clone();
+        change(&mut value);
+        config
+            .borrow_mut()
+            .set(value)
+            .expect("Cannot update the config");
+    });
+}
+
+// admin function to turn read logging for get_product_logged on or off
+#[ic_cdk::update]
+fn set_log_reads(enabled: bool) -> Result<(), Error> {
+    require_authenticated()?;
+    require_controller()?;
+    update_config(|config| config.log_reads = enabled);
+    Ok(())
+}
+
+// Append the current cycles balance to the cycle usage log
+fn sample_cycles() {
+    CYCLE_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let balance = ic_cdk::api::canister_balance128();
+        let (key, consumed) = usage.last_key_value().map_or((0, 0), |(key, last)| {
+            (key + 1, last.balance.saturating_sub(balance))
+        });
+        usage.insert(
+            key,
+            CycleSample {
+                timestamp: time(),
+                balance,
+                consumed,
+            },
+        );
+        // keep only the most recent samples
+        while usage.len() > CYCLE_USAGE_CAPACITY {
+            let oldest = usage.first_key_value().map(|(key, _)| key).unwrap();
+            usage.remove(&oldest);
+        }
+    });
+}
+
+// Start or stop the cycle sampling timer
+fn schedule_cycle_sampling(enabled: bool) {
+    CYCLE_TIMER.with(|timer| {
+        if let Some(id) = timer.borrow_mut().take() {
+            ic_cdk_timers::clear_timer(id);
+        }
+        if enabled {
+            sample_cycles();
+            *timer.borrow_mut() = Some(ic_cdk_timers::set_timer_interval(
+                CYCLE_SAMPLE_INTERVAL,
+                sample_cycles,
+            ));
+        }
+    });
+}
+
+// admin function to turn periodic sampling of the cycles balance on or off
+#[ic_cdk::update]
+fn set_cycle_tracking(enabled: bool) -> Result<(), Error> {
+    require_authenticated()?;
+    require_controller()?;
+    update_config(|config| config.track_cycles = enabled);
+    schedule_cycle_sampling(enabled);
+    Ok(())
+}
+
+// admin function to get the sampled cycles balances, oldest first
+#[ic_cdk::query]
+fn get_cycle_usage() -> Result<Vec<CycleSample>, Error> {
+    require_controller()?;
+    Ok(CYCLE_USAGE.with(|usage| usage.borrow().iter().map(|(_, sample)| sample).collect()))
+}
+
+// admin function to get the logged reads of a product, oldest first
+#[ic_cdk::query]
+fn get_access_log(product_id: u64) -> Result<Vec<AccessLogEntry>, Error> {
+    require_controller()?;
+    Ok(ACCESS_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.product_id == product_id)
+            .collect()
+    }))
+}
+
+// Most products and most warehouses in one page of an export
+const MAX_SNAPSHOT_PAGE: u64 = 100;
+
+// export a page of the products and a page of the warehouses, both in id order, together
+// with the id counter. export pages with growing offsets until offset reaches both totals
+#[ic_cdk::query]
+fn export_snapshot(offset: u64, limit: u64) -> Result<Snapshot, Error> {
+    let limit = limit.min(MAX_SNAPSHOT_PAGE) as usize;
+    let products: Vec<Product> = PRODUCT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .skip(offset as usize)
+            .take(limit)
+            .map(|(_, product)| product)
+            .collect()
+    });
+    let warehouses: Vec<Warehouse> = WAREHOUSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .skip(offset as usize)
+            .take(limit)
+            .map(|(_, warehouse)| warehouse)
+            .collect()
+    });
+    check_response_size(&products)?;
+    check_response_size(&warehouses)?;
+
+    Ok(Snapshot {
+        products,
+        warehouses,
+        next_id: ID_COUNTER.with(|counter| *counter.borrow().get()),
+        exported_at: time(),
+        product_total: PRODUCT_STORAGE.with(|s| s.borrow().len()),
+        warehouse_total: WAREHOUSE_STORAGE.with(|s| s.borrow().len()),
+    })
+}
+
+// Remove every entry of a map, returning how many were removed
+fn clear_map<K: BoundedStorable + Ord + Clone, V: BoundedStorable>(
+    map: &mut StableBTreeMap<K, V, Memory>,
+) -> u64 {
+    let keys: Vec<K> = map.iter().map(|(key, _)| key).collect();
+    for key in &keys {
+        map.remove(key);
+    }
+    keys.len() as u64
+}
+
+// admin function to wipe all products, warehouses and their history. the id counter is
+// reset to 0 unless preserve_ids is set, in which case new ids continue after the old ones
+#[ic_cdk::update]
+fn clear_all(preserve_ids: bool) -> Result<ClearResult, Error> {
+    require_authenticated()?;
+    require_controller()?;
+
+    let products_removed = PRODUCT_STORAGE.with(|s| clear_map(&mut s.borrow_mut()));
+    let warehouses_removed = WAREHOUSE_STORAGE.with(|s| clear_map(&mut s.borrow_mut()));
+    PRICE_HISTORY.with(|s| clear_map(&mut s.borrow_mut()));
+    MOVEMENT_STORAGE.with(|s| clear_map(&mut s.borrow_mut()));
+    ALERT_STORAGE.with(|s| clear_map(&mut s.borrow_mut()));
+    ACCESS_LOG.with(|s| clear_map(&mut s.borrow_mut()));
+    CYCLE_USAGE.with(|s| clear_map(&mut s.borrow_mut()));
+    OWNERSHIP_HISTORY.with(|s| clear_map(&mut s.borrow_mut()));
+    DELIVERY_LOG.with(|s| clear_map(&mut s.borrow_mut()));
+    DAILY_DISPENSED.with(|s| clear_map(&mut s.borrow_mut()));
+
+    if !preserve_ids {
+        ID_COUNTER
+            .with(|counter| counter.borrow_mut().set(0))
+            .expect("Cannot reset the id counter");
+    }
+
+    Ok(ClearResult {
+        products_removed,
+        warehouses_removed,
+        ids_preserved: preserve_ids,
+    })
+}
+
+// admin function to restore a snapshot one page at a time. the first page is imported with
+// replace set, which drops all products, warehouses and their history, and every later page
+// adds its records to those already imported
+#[ic_cdk::update]
+fn import_snapshot(snapshot: Snapshot, replace: bool) -> Result<(), Error> {
+    require_authenticated()?;
+    require_controller()?;
+
+    // products and warehouses share one id counter, so ids must be unique across both
+    let mut ids: BTreeSet<u64> = BTreeSet::new();
+    if !replace {
+        PRODUCT_STORAGE.with(|s| ids.extend(s.borrow().iter().map(|(id, _)| id)));
+        WAREHOUSE_STORAGE.with(|s| ids.extend(s.borrow().iter().map(|(id, _)| id)));
+    }
+    let all_ids = snapshot
+        .products
+        .iter()
+        .map(|product| product.id)
+        .chain(snapshot.warehouses.iter().map(|warehouse| warehouse.id));
+    for id in all_ids {
+        if !ids.insert(id) {
+            return Err(Error::InvalidPayload {
+                msg: format!("id: {} appears more than once in the snapshot", id),
+            });
+        }
+        if id >= snapshot.next_id {
+            return Err(Error::InvalidPayload {
+                msg: format!("id: {} is not below next_id: {}", id, snapshot.next_id),
+            });
+        }
+    }
+    for product in &snapshot.products {
+        check_stored_size(product, "product")?;
+    }
+    for warehouse in &snapshot.warehouses {
+        check_stored_size(warehouse, "warehouse")?;
+    }
+
+    if replace {
+        // the history refers to the records being replaced
+        PRODUCT_STORAGE.with(|s| clear_map(&mut s.borrow_mut()));
+        WAREHOUSE_STORAGE.with(|s| clear_map(&mut s.borrow_mut()));
+        PRICE_HISTORY.with(|s| clear_map(&mut s.borrow_mut()));
+        MOVEMENT_STORAGE.with(|s| clear_map(&mut s.borrow_mut()));
+        ALERT_STORAGE.with(|s| clear_map(&mut s.borrow_mut()));
+        ACCESS_LOG.with(|s| clear_map(&mut s.borrow_mut()));
+        OWNERSHIP_HISTORY.with(|s| clear_map(&mut s.borrow_mut()));
+        DELIVERY_LOG.with(|s| clear_map(&mut s.borrow_mut()));
+        DAILY_DISPENSED.with(|s| clear_map(&mut s.borrow_mut()));
+    }
+    PRODUCT_STORAGE.with(|s| {
+        let mut products = s.borrow_mut();
+        for product in snapshot.products {
+            products.insert(product.id, product);
+        }
+    });
+    WAREHOUSE_STORAGE.with(|s| {
+        let mut warehouses = s.borrow_mut();
+        for warehouse in snapshot.warehouses {
+            warehouses.insert(warehouse.id, warehouse);
+        }
+    });
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(snapshot.next_id))
+        .expect("Cannot restore the id counter");
+
+    Ok(())
+}
+
+// cheap liveness check reporting storage sizes, stable memory use and the cycles balance
+#[ic_cdk::query]
+fn health_check() -> HealthStatus {
+    HealthStatus {
+        product_count: PRODUCT_STORAGE.with(|s| s.borrow().len()),
+        warehouse_count: WAREHOUSE_STORAGE.with(|s| s.borrow().len()),
+        stable_memory_pages: ic_cdk::api::stable::stable64_size(),
+        cycles_balance: ic_cdk::api::canister_balance128(),
+        timestamp: time(),
+    }
+}
+
+// get the currency unit prices are denominated in
+#[ic_cdk::query]
+fn get_currency_config() -> CurrencyConfig {
+    config().currency
+}
+
+// Define an Error enum for handling errors
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    NotFound { msg: String },
+    NotFoundWithSuggestions { msg: String, nearest: Vec<u64> },
+    AlreadyInit { msg: String },
+    InvalidPayload { msg: String },
+    Unauthorized { msg: String },
+    VersionConflict { expected: u64, actual: u64 },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NotFound { msg }
+            | Error::NotFoundWithSuggestions { msg, .. }
+            | Error::AlreadyInit { msg }
+            | Error::InvalidPayload { msg }
+            | Error::Unauthorized { msg } => write!(f, "{}", msg),
+            Error::VersionConflict { expected, actual } => write!(
+                f,
+                "version conflict: expected {} but the product is at {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+// Candid generator for exporting the Candid interface
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    thread_local! {
+        // stand-ins for the system API, each test thread starts at time 1 called by USER
+        static NOW: std::cell::Cell<u64> = const { std::cell::Cell::new(1) };
+        static CALLER: std::cell::Cell<Principal> = const { std::cell::Cell::new(USER) };
+        static CONTROLLERS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+        static LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    const USER: Principal = Principal::from_slice(&[1; 29]);
+
+    pub(super) fn time() -> u64 {
+        NOW.with(|now| now.get())
+    }
+
+    pub(super) fn caller() -> Principal {
+        CALLER.with(|caller| caller.get())
+    }
+
+    pub(super) fn is_controller(principal: &Principal) -> bool {
+        CONTROLLERS.with(|controllers| controllers.borrow().contains(principal))
+    }
+
+    pub(super) fn print<S: AsRef<str>>(line: S) {
+        LOG.with(|log| log.borrow_mut().push(line.as_ref().to_string()));
+    }
+
+    // lines printed to the canister log by this test thread
+    fn printed() -> Vec<String> {
+        LOG.with(|log| log.borrow().clone())
+    }
+
+    fn set_time(ns: u64) {
+        NOW.with(|now| now.set(ns));
+    }
+
+    fn call_as(principal: Principal) {
+        CALLER.with(|caller| caller.set(principal));
+    }
+
+    fn add_controller(principal: Principal) {
+        CONTROLLERS.with(|controllers| controllers.borrow_mut().push(principal));
+    }
+
+    fn warehouse_payload(name: &str) -> WarehousePayload {
+        WarehousePayload {
+            name: name.to_string(),
+            address: "Kenyatta Avenue".to_string(),
+            city: "Nairobi".to_string(),
+            contact_email: "ops@example.com".to_string(),
+            contact_phone: "+254 700 000000".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn product_payload(name: &str, warehouse_id: u64, quantity: u32) -> ProductPayload {
+        ProductPayload {
+            name: name.to_string(),
+            category: "Medicine".to_string(),
+            quantity,
+            unit_of_measure: "box".to_string(),
+            warehouse_id,
+            ..Default::default()
+        }
+    }
+
+    // unwrap a result, panicking with the error's message
+    fn ok<T>(result: Result<T, Error>) -> T {
+        result.unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn new_warehouse(name: &str) -> Warehouse {
+        ok(add_warehouse(warehouse_payload(name)))
+    }
+
+    fn new_product(name: &str, warehouse_id: u64, quantity: u32) -> Product {
+        ok(add_product(product_payload(name, warehouse_id, quantity)))
+    }
+
+    // Warehouse and product in the shape the first version of the canister stored
+    #[derive(candid::CandidType)]
+    struct BaselineWarehouse {
+        id: u64,
+        name: String,
+        address: String,
+    }
+
+    #[derive(candid::CandidType)]
+    struct BaselineProduct {
+        id: u64,
+        name: String,
+        quantity: u32,
+        category: String,
+        warehouse: BaselineWarehouse,
+        added_at: u64,
+        re_stocked_at: u64,
+    }
+
+    fn baseline_product(id: u64) -> BaselineProduct {
+        BaselineProduct {
+            id,
+            name: "Paracetamol".to_string(),
+            quantity: 40,
+            category: "Medicine".to_string(),
+            warehouse: BaselineWarehouse {
+                id: 1,
+                name: "Central".to_string(),
+                address: "Kenyatta Avenue".to_string(),
+            },
+            added_at: 10,
+            re_stocked_at: 20,
+        }
+    }
+
+    #[test]
+    fn current_records_round_trip() {
+        let product = Product {
+            schema_version: PRODUCT_SCHEMA_VERSION,
+            id: 7,
+            name: "Paracetamol".to_string(),
+            image_url: Some("https://example.com/p.png".to_string()),
+            ..Default::default()
+        };
+        let decoded = Product::from_bytes(product.to_bytes());
+        assert_eq!(decoded.id, 7);
+        assert_eq!(decoded.name, "Paracetamol");
+        assert_eq!(decoded.image_url, product.image_url);
+
+        let movement = StockMovement {
+            id: 3,
+            delta: -5,
+            reason: MovementReason::Dispense,
+            reversed: true,
+            ..Default::default()
+        };
+        let decoded = StockMovement::from_bytes(movement.to_bytes());
+        assert_eq!(decoded.delta, -5);
+        assert!(decoded.reason == MovementReason::Dispense && decoded.reversed);
+    }
+
+    #[test]
+    fn baseline_product_keeps_its_data() {
+        let bytes = Encode!(&baseline_product(2)).unwrap();
+        let product = Product::from_bytes(Cow::Owned(bytes));
+
+        assert_eq!(product.id, 2);
+        assert_eq!(product.name, "Paracetamol");
+        assert_eq!(product.quantity, 40);
+        assert_eq!(product.unit_of_measure, "each");
+        assert_eq!((product.added_at, product.re_stocked_at), (10, 20));
+        assert_eq!(product.warehouse_id, 1);
+        assert_eq!(product.schema_version, 0);
+    }
+
+    #[test]
+    fn baseline_warehouse_keeps_its_data() {
+        let bytes = Encode!(&BaselineWarehouse {
+            id: 4,
+            name: "Central".to_string(),
+            address: "Kenyatta Avenue".to_string(),
+        })
+        .unwrap();
+        let warehouse = Warehouse::from_bytes(Cow::Owned(bytes));
+
+        assert_eq!(warehouse.id, 4);
+        assert_eq!(warehouse.address, "Kenyatta Avenue");
+        assert!(warehouse.is_active);
+        assert_eq!(warehouse.owner, Principal::anonymous());
+    }
+
+    #[test]
+    fn movement_from_before_undo_decodes() {
+        #[derive(candid::CandidType)]
+        struct FirstMovement {
+            id: u64,
+            product_id: u64,
+            delta: i64,
+            reason: String,
+            timestamp: u64,
+        }
+        let bytes = encode_versioned(&FirstMovement {
+            id: 1,
+            product_id: 2,
+            delta: -3,
+            reason: "order".to_string(),
+            timestamp: 4,
+        });
+        let movement = StockMovement::from_bytes(bytes);
+
+        assert!(movement.reason == MovementReason::Dispense);
+        assert_eq!(movement.note.as_deref(), Some("order"));
+        assert!(!movement.reversed);
+    }
+
+    #[test]
+    fn undecodable_record_falls_back_to_default() {
+        let product = Product::from_bytes(Cow::Owned(vec![STORAGE_VERSION, 1, 2, 3]));
+
+        assert_eq!(product.id, 0);
+        assert!(product.name.is_empty());
+        let log = printed();
+        assert_eq!(log.len(), 1);
+        assert!(log[0].starts_with("warning: stored product replaced by a default"));
+    }
+
+    #[test]
+    fn unknown_storage_version_falls_back_to_default() {
+        let mut bytes = Warehouse {
+            id: 9,
+            name: "Central".to_string(),
+            ..Default::default()
+        }
+        .to_bytes()
+        .into_owned();
+        bytes[0] = STORAGE_VERSION + 1;
+        let warehouse = Warehouse::from_bytes(Cow::Owned(bytes));
+
+        assert_eq!(warehouse.id, 0);
+        assert!(warehouse.name.is_empty());
+        assert_eq!(
+            printed(),
+            vec!["warning: stored warehouse replaced by a default, unknown storage version"]
+        );
+    }
+
+    #[test]
+    fn records_are_stored_behind_the_version_byte() {
+        let warehouse = Warehouse {
+            id: 9,
+            name: "Central".to_string(),
+            ..Default::default()
+        };
+        let bytes = warehouse.to_bytes();
+        assert_eq!(bytes[0], STORAGE_VERSION);
+        assert!(bytes[1..].starts_with(b"DIDL"));
+
+        let decoded = Warehouse::from_bytes(bytes);
+        assert_eq!((decoded.id, decoded.name.as_str()), (9, "Central"));
+    }
+
+    #[test]
+    fn migration_keeps_records_under_their_keys() {
+        PRODUCT_STORAGE.with(|s| {
+            let mut products = s.borrow_mut();
+            for key in [3, 5, 8] {
+                let bytes = Encode!(&baseline_product(key)).unwrap();
+                products.insert(key, Product::from_bytes(Cow::Owned(bytes)));
+            }
+        });
+
+        assert_eq!(migrate_stored_products(), 3);
+        PRODUCT_STORAGE.with(|s| {
+            let products = s.borrow();
+            assert_eq!(products.len(), 3);
+            for (key, product) in products.iter() {
+                assert_eq!(product.id, key);
+                assert_eq!(product.display_id, display_id("PRD", key));
+                assert_eq!(product.schema_version, PRODUCT_SCHEMA_VERSION);
+                assert_eq!(product.quantity, 40);
+            }
+        });
+        assert_eq!(migrate_stored_products(), 0);
+    }
+
+    #[test]
+    fn migration_skips_current_warehouses() {
+        let current = new_warehouse("Central");
+        let bytes = Encode!(&BaselineWarehouse {
+            id: 40,
+            name: "Coast".to_string(),
+            address: "Moi Avenue".to_string(),
+        })
+        .unwrap();
+        WAREHOUSE_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert(40, Warehouse::from_bytes(Cow::Owned(bytes)))
+        });
+
+        assert_eq!(migrate_stored_warehouses(), 1);
+        let migrated = ok(get_warehouse_by_id(40));
+        assert_eq!(migrated.schema_version, WAREHOUSE_SCHEMA_VERSION);
+        assert_eq!(migrated.display_id, display_id("WHS", 40));
+        assert_eq!(
+            ok(get_warehouse_by_id(current.id)).schema_version,
+            WAREHOUSE_SCHEMA_VERSION
+        );
+        assert_eq!(migrate_stored_warehouses(), 0);
+    }
+
+    #[test]
+    fn largest_product_fits_its_bound() {
+        let label = "x".repeat(MAX_LABEL_BYTES);
+        let product = Product {
+            schema_version: u16::MAX,
+            id: u64::MAX,
+            display_id: display_id(&"P".repeat(MAX_ID_PREFIX_BYTES), u64::MAX),
+            name: "n".repeat(MAX_NAME_BYTES),
+            unit_of_measure: label.clone(),
+            category: label.clone(),
+            // the longest url validate_image_url accepts
+            image_url: Some(format!("https://{}", "u".repeat(511 - 8))),
+            lot_number: label,
+            ..Default::default()
+        };
+        assert!(check_stored_size(&product, "product").is_ok());
+
+        let oversized = Product {
+            name: "n".repeat(1024),
+            ..product
+        };
+        assert!(matches!(
+            check_stored_size(&oversized, "product"),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn largest_warehouse_fits_its_bound() {
+        let text = |max: usize| "x".repeat(max);
+        let warehouse = Warehouse {
+            schema_version: u16::MAX,
+            id: u64::MAX,
+            display_id: display_id(&"W".repeat(MAX_ID_PREFIX_BYTES), u64::MAX),
+            name: text(MAX_NAME_BYTES),
+            address: text(MAX_ADDRESS_BYTES),
+            city: text(MAX_CITY_BYTES),
+            // the longest principal there is
+            owner: Principal::from_slice(&[0xff; 29]),
+            capacity_mode: CapacityMode::Volume,
+            allowed_categories: vec![text(MAX_LABEL_BYTES); MAX_ALLOWED_CATEGORIES],
+            contact_email: text(MAX_CONTACT_EMAIL_BYTES),
+            contact_phone: text(MAX_CONTACT_PHONE_BYTES),
+            opening_hours: vec![
+                (LAST_WEEKDAY, MINUTES_PER_DAY - 2, MINUTES_PER_DAY - 1);
+                MAX_OPENING_WINDOWS
+            ],
+            coordinates: Some((90.0, 180.0)),
+            tags: vec![text(MAX_TAG_BYTES); MAX_WAREHOUSE_TAGS],
+            ..Default::default()
+        };
+        assert!(check_stored_size(&warehouse, "warehouse").is_ok());
+    }
+
+    #[test]
+    fn largest_movement_fits_its_bound() {
+        let movement = StockMovement {
+            id: u64::MAX,
+            product_id: u64::MAX,
+            delta: i64::MIN,
+            reason: MovementReason::Reconcile,
+            note: Some("é".repeat(MAX_NOTE_BYTES / 2)),
+            timestamp: u64::MAX,
+            reversed: true,
+            reverses: Some(u64::MAX),
+        };
+        assert!(check_stored_size(&movement, "movement").is_ok());
+    }
+
+    #[test]
+    fn unit_of_measure_round_trips() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        assert_eq!(product.unit_of_measure, "box");
+        let stored = ok(get_product_by_id(product.id));
+        assert_eq!(stored.unit_of_measure, "box");
+    }
+
+    #[test]
+    fn unit_of_measure_is_required() {
+        let warehouse = new_warehouse("Central");
+        let payload = ProductPayload {
+            unit_of_measure: String::new(),
+            ..product_payload("Paracetamol", warehouse.id, 10)
+        };
+
+        assert!(matches!(
+            add_product(payload),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    fn order_line(product_id: u64, amount: u32) -> GetProductPayload {
+        GetProductPayload {
+            product_id,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dry_run_order_leaves_stock_unchanged() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        let receipts = ok(consume_for_order(
+            vec![order_line(product.id, 3), order_line(product.id, 4)],
+            true,
+        ));
+
+        assert_eq!(receipts.len(), 2);
+        assert!(receipts.iter().all(|receipt| receipt.dry_run));
+        assert_eq!(receipts[1].remaining, 3);
+        assert_eq!(ok(get_product_by_id(product.id)).quantity, 10);
+        assert_eq!(get_product_movements(product.id).len(), 1);
+    }
+
+    #[test]
+    fn order_takes_the_stock() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        ok(consume_for_order(vec![order_line(product.id, 3)], false));
+
+        assert_eq!(ok(get_product_by_id(product.id)).quantity, 7);
+    }
+
+    #[test]
+    fn dry_run_reports_an_order_that_doesnt_fit() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        let result = consume_for_order(
+            vec![order_line(product.id, 6), order_line(product.id, 6)],
+            true,
+        );
+
+        assert!(matches!(result, Err(Error::InvalidPayload { .. })));
+    }
+
+    #[test]
+    fn restock_stops_at_max_stock() {
+        let warehouse = new_warehouse("Central");
+        let product = ok(add_product(ProductPayload {
+            max_stock: 10,
+            ..product_payload("Paracetamol", warehouse.id, 8)
+        }));
+
+        let restocked = ok(add_product_to_warehouse(order_line(product.id, 2)));
+        assert_eq!(restocked.quantity, 10);
+        assert!(matches!(
+            add_product_to_warehouse(order_line(product.id, 1)),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn product_cant_start_above_max_stock() {
+        let warehouse = new_warehouse("Central");
+        let payload = ProductPayload {
+            max_stock: 10,
+            ..product_payload("Paracetamol", warehouse.id, 11)
+        };
+
+        assert!(matches!(
+            add_product(payload),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("paracetamol", "paracetamol"), 0);
+        assert_eq!(edit_distance("paracetmol", "paracetamol"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn fuzzy_search_forgives_typos() {
+        let warehouse = new_warehouse("Central");
+        new_product("Paracetamol", warehouse.id, 10);
+        new_product("Ibuprofen", warehouse.id, 10);
+
+        let found = ok(search_products("paracetmol".to_string(), true));
+        assert_eq!(found.items.len(), 1);
+        assert_eq!(found.items[0].name, "Paracetamol");
+        assert!(search_products("paracetmol".to_string(), false).is_err());
+    }
+
+    #[test]
+    fn display_ids_round_trip() {
+        assert_eq!(display_id("PRD", 123), "PRD-000123");
+        let warehouse = new_warehouse("Central");
+        assert_eq!(warehouse.display_id, display_id("WHS", warehouse.id));
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        let found = ok(get_product_by_display_id(product.display_id.clone()));
+        assert_eq!(found.id, product.id);
+        assert!(get_product_by_display_id("PRD-999999".to_string()).is_err());
+    }
+
+    #[test]
+    fn display_ids_use_the_configured_prefix() {
+        update_config(|config| config.product_id_prefix = "SKU".to_string());
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        assert_eq!(product.display_id, display_id("SKU", product.id));
+    }
+
+    #[test]
+    fn normalize_trims_and_collapses_whitespace() {
+        assert_eq!(normalize("  Main \t Depot  "), "Main Depot");
+        assert_eq!(normalize("   "), "");
+    }
+
+    #[test]
+    fn warehouse_strings_are_stored_normalized() {
+        let warehouse = ok(add_warehouse(WarehousePayload {
+            address: " Kenyatta   Avenue ".to_string(),
+            city: "Nairobi ".to_string(),
+            ..warehouse_payload("  Main  Depot")
+        }));
+
+        assert_eq!(warehouse.name, "Main Depot");
+        assert_eq!(warehouse.address, "Kenyatta Avenue");
+        assert_eq!(warehouse.city, "Nairobi");
+    }
+
+    #[test]
+    fn padded_names_count_as_duplicates() {
+        let result = add_warehouses_batch(vec![
+            warehouse_payload(" Main Depot"),
+            warehouse_payload("Main Depot "),
+        ]);
+
+        assert!(matches!(result, Err(Error::InvalidPayload { .. })));
+    }
+
+    #[test]
+    fn warehouses_by_ids_keeps_order_and_reports_missing_ids() {
+        let central = new_warehouse("Central");
+        let coast = new_warehouse("Coast");
+
+        let found = get_warehouses_by_ids(vec![coast.id, 999, central.id, 998]);
+
+        assert_eq!(found.len(), 4);
+        assert!(matches!(&found[0], Ok(warehouse) if warehouse.id == coast.id));
+        assert!(matches!(found[1], Err(999)));
+        assert!(matches!(&found[2], Ok(warehouse) if warehouse.id == central.id));
+        assert!(matches!(found[3], Err(998)));
+    }
+
+    #[test]
+    fn warehouses_by_ids_looks_up_at_most_200() {
+        assert_eq!(get_warehouses_by_ids((0..250).collect()).len(), 200);
+    }
+
+    #[test]
+    fn quantity_sums_widen_before_adding() {
+        let warehouse = new_warehouse("Central");
+        PRODUCT_STORAGE.with(|s| {
+            let mut products = s.borrow_mut();
+            for id in 100..105 {
+                let product = Product {
+                    id,
+                    quantity: u32::MAX,
+                    warehouse_id: warehouse.id,
+                    ..Default::default()
+                };
+                products.insert(id, product);
+            }
+        });
+        let expected = 5 * u32::MAX as u64;
+
+        assert_eq!(warehouse_used_quantity(warehouse.id), expected);
+        assert_eq!(
+            ok(get_warehouse_summary(warehouse.id)).total_quantity,
+            expected
+        );
+    }
+
+    #[test]
+    fn quantity_delta_doesnt_wrap() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 0);
+        for _ in 0..3 {
+            record_movement(product.id, u32::MAX as i64, MovementReason::Restock, None);
+        }
+        record_movement(
+            product.id,
+            -(u32::MAX as i64),
+            MovementReason::Dispense,
+            None,
+        );
+
+        let delta = ok(get_quantity_delta(product.id, 0));
+        assert_eq!(delta.inbound, 3 * u32::MAX as u64);
+        assert_eq!(delta.outbound, u32::MAX as u64);
+        assert_eq!(delta.net, 2 * u32::MAX as i64);
+    }
+
+    #[test]
+    fn warehouse_contacts_are_validated() {
+        assert!(validate_phone("+254 700 000000").is_ok());
+        assert!(validate_phone("0700-000-000").is_err());
+        assert!(validate_phone("call me").is_err());
+        assert!(validate_phone("").is_ok());
+        let long_city = "x".repeat(MAX_CITY_BYTES + 1);
+        let message = validate_city(&long_city).unwrap_err().to_string();
+        assert_eq!(
+            message,
+            format!("city must be at most {} bytes", MAX_CITY_BYTES)
+        );
+
+        // contact details are optional
+        ok(add_warehouse(WarehousePayload {
+            contact_email: String::new(),
+            contact_phone: String::new(),
+            ..warehouse_payload("Lake")
+        }));
+
+        let no_at = WarehousePayload {
+            contact_email: "ops.example.com".to_string(),
+            ..warehouse_payload("Central")
+        };
+        assert!(matches!(
+            add_warehouse(no_at),
+            Err(Error::InvalidPayload { .. })
+        ));
+        let bad_phone = WarehousePayload {
+            contact_phone: "ext. 12".to_string(),
+            ..warehouse_payload("Central")
+        };
+        assert!(matches!(
+            add_warehouse(bad_phone),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn warehouse_contacts_can_be_edited() {
+        let warehouse = new_warehouse("Central");
+
+        let edited = ok(edit_warehouse(EditWarehousePayload {
+            warehouse_id: warehouse.id,
+            name: "Central".to_string(),
+            contact_email: Some("depot@example.com".to_string()),
+            ..Default::default()
+        }));
+
+        assert_eq!(edited.contact_email, "depot@example.com");
+        assert_eq!(edited.contact_phone, "+254 700 000000");
+    }
+
+    #[test]
+    fn transfer_to_the_same_warehouse_is_rejected() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        let result = transfer_product(product.id, warehouse.id, 4);
+
+        assert!(matches!(result, Err(Error::InvalidPayload { msg }) if msg.contains("same")));
+        assert_eq!(ok(get_product_by_id(product.id)).quantity, 10);
+        assert_eq!(get_product_movements(product.id).len(), 1);
+    }
+
+    #[test]
+    fn clear_all_resets_ids_by_default() {
+        add_controller(USER);
+        let warehouse = new_warehouse("Central");
+        new_product("Paracetamol", warehouse.id, 10);
+
+        let result = ok(clear_all(false));
+
+        assert_eq!((result.products_removed, result.warehouses_removed), (1, 1));
+        assert!(!result.ids_preserved);
+        assert_eq!(new_warehouse("Central").id, 0);
+    }
+
+    #[test]
+    fn clear_all_can_keep_ids_unique() {
+        add_controller(USER);
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        let result = ok(clear_all(true));
+
+        assert!(result.ids_preserved);
+        assert_eq!(get_product_ids(), Vec::<u64>::new());
+        assert_eq!(new_warehouse("Central").id, product.id + 1);
+    }
+
+    #[test]
+    fn clear_all_is_for_controllers() {
+        assert!(matches!(clear_all(false), Err(Error::Unauthorized { .. })));
+    }
+
+    #[test]
+    fn next_id_increases_strictly() {
+        let ids: Vec<u64> = (0..5).map(|_| ok(next_id())).collect();
+
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+        assert_eq!(peek_next_id(), 5);
+    }
+
+    #[test]
+    fn rejected_adds_dont_take_an_id() {
+        let warehouse = new_warehouse("Central");
+        let next = peek_next_id();
+
+        assert!(add_product(product_payload("Paracetamol", 999, 10)).is_err());
+        assert!(add_product(product_payload("", warehouse.id, 10)).is_err());
+        assert!(add_warehouse(warehouse_payload("")).is_err());
+
+        assert_eq!(peek_next_id(), next);
+        assert_eq!(new_warehouse("Coast").id, next);
+    }
+
+    #[test]
+    fn min_name_length_is_configurable() {
+        let warehouse = new_warehouse("Central");
+        assert!(add_product(product_payload("AA", warehouse.id, 10)).is_err());
+
+        update_config(|config| config.min_name_length = 2);
+
+        assert_eq!(new_product("AA", warehouse.id, 10).name, "AA");
+    }
+
+    #[test]
+    fn renames_are_validated() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+
+        let rename_product = edit_product(EditProductPayload {
+            name: "AA".to_string(),
+            product_id: product.id,
+            expected_version: product.version,
+            ..Default::default()
+        });
+        let rename_warehouse = edit_warehouse(EditWarehousePayload {
+            warehouse_id: warehouse.id,
+            name: "x".repeat(MAX_NAME_BYTES + 1),
+            ..Default::default()
+        });
+
+        assert!(matches!(rename_product, Err(Error::InvalidPayload { .. })));
+        assert!(matches!(
+            rename_warehouse,
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn transfer_records_both_sides() {
+        let central = new_warehouse("Central");
+        let coast = new_warehouse("Coast");
+        let product = ok(add_product(ProductPayload {
+            safety_stock: 2,
+            ..product_payload("Paracetamol", central.id, 10)
+        }));
+
+        let receipt = ok(transfer_product(product.id, coast.id, 4));
+
+        assert_eq!((receipt.transferred, receipt.source_remaining), (4, 6));
+        assert_eq!(receipt.target_total, 4);
+        let outbound = get_product_movements(product.id);
+        assert!(outbound.last().is_some_and(|movement| {
+            movement.reason == MovementReason::TransferOut && movement.delta == -4
+        }));
+        let inbound = get_product_movements(receipt.target_product_id);
+        assert!(inbound.last().is_some_and(|movement| {
+            movement.reason == MovementReason::TransferIn && movement.delta == 4
+        }));
+        // the new lot doesn't take over the source's reservation
+        let lot = ok(get_product_by_id(receipt.target_product_id));
+        assert_eq!((lot.warehouse_id, lot.safety_stock), (coast.id, 0));
+    }
+
+    #[test]
+    fn warehouse_minimum_initial_stock_is_enforced() {
+        let central = new_warehouse("Central");
+        let bulk = ok(add_warehouse(WarehousePayload {
+            min_initial_stock: 10,
+            ..warehouse_payload("Bulk")
+        }));
+
+        assert!(matches!(
+            add_product(product_payload("Paracetamol", bulk.id, 5)),
+            Err(Error::InvalidPayload { .. })
+        ));
+        assert_eq!(new_product("Paracetamol", bulk.id, 10).quantity, 10);
+
+        // a clone starts empty and a transfer opening a lot has to bring the minimum
+        let product = new_product("Ibuprofen", central.id, 20);
+        assert!(clone_product(product.id, "Aspirin".to_string(), bulk.id).is_err());
+        assert!(transfer_product(product.id, bulk.id, 5).is_err());
+        assert!(transfer_product(product.id, bulk.id, 10).is_ok());
+    }
+
+    #[test]
+    fn category_limit_rejects_a_new_category() {
+        let warehouse = ok(add_warehouse(WarehousePayload {
+            max_distinct_categories: 2,
+            ..warehouse_payload("Central")
+        }));
+        let in_category = |name: &str, category: &str| ProductPayload {
+            category: category.to_string(),
+            ..product_payload(name, warehouse.id, 10)
+        };
+        ok(add_product(in_category("Paracetamol", "Medicine")));
+        ok(add_product(in_category("Bandage", "First Aid")));
+
+        // categories already held are compared case insensitively
+        assert!(add_product(in_category("Ibuprofen", "medicine")).is_ok());
+        assert!(matches!(
+            add_product(in_category("Shampoo", "Toiletries")),
+            Err(Error::InvalidPayload { .. })
+        ));
+        assert_eq!(
+            ok(get_warehouse_categories(warehouse.id)),
+            vec!["First Aid".to_string(), "Medicine".to_string()]
+        );
+    }
+
+    #[test]
+    fn anonymous_callers_cant_update() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+        call_as(Principal::anonymous());
+
+        assert!(matches!(
+            require_authenticated(),
+            Err(Error::Unauthorized { .. })
+        ));
+        let rejected =
+            |result: Result<(), Error>| matches!(result, Err(Error::Unauthorized { .. }));
+        assert!(rejected(
+            add_product(product_payload("Ibuprofen", warehouse.id, 10)).map(drop)
+        ));
+        assert!(rejected(
+            add_warehouse(warehouse_payload("Coast")).map(drop)
+        ));
+        assert!(rejected(
+            add_product_to_warehouse(order_line(product.id, 5)).map(drop)
+        ));
+        assert!(rejected(
+            remove_product_from_warehouse(order_line(product.id, 5)).map(drop)
+        ));
+        assert!(rejected(restock_to_level(product.id, 20).map(drop)));
+        assert!(rejected(
+            reconcile_product(product.id, 0, "count".to_string()).map(drop)
+        ));
+        assert!(rejected(get_product_logged(product.id).map(drop)));
+        assert_eq!(ok(get_product_by_id(product.id)).quantity, 10);
+    }
+
+    #[test]
+    fn edits_leave_quantity_untouched() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+        let edit = EditProductPayload {
+            name: "Paracetamol 500mg".to_string(),
+            product_id: product.id,
+            expected_version: product.version,
+            ..Default::default()
+        };
+
+        let result = edit_product(EditProductPayload {
+            quantity: Some(50),
+            ..edit.clone()
+        });
+        assert!(
+            matches!(result, Err(Error::InvalidPayload { msg }) if msg.contains("add_product_to_warehouse"))
+        );
+        assert_eq!(ok(get_product_by_id(product.id)).quantity, 10);
+
+        let edited = ok(edit_product(edit));
+        assert_eq!(edited.name, "Paracetamol 500mg");
+        assert_eq!(edited.quantity, 10);
+    }
+
+    #[test]
+    fn orders_count_against_the_daily_limit() {
+        add_controller(USER);
+        let warehouse = ok(add_warehouse(WarehousePayload {
+            daily_dispense_limit: 5,
+            ..warehouse_payload("Central")
+        }));
+        let product = new_product("Paracetamol", warehouse.id, 20);
+
+        let too_much = vec![order_line(product.id, 3), order_line(product.id, 3)];
+        assert!(consume_for_order(too_much, false).is_err());
+        ok(consume_for_order(vec![order_line(product.id, 3)], false));
+        ok(remove_product_from_warehouse(order_line(product.id, 2)));
+        assert!(remove_product_from_warehouse(order_line(product.id, 1)).is_err());
+
+        // undoing a dispense gives its units back to the day's allowance
+        ok(undo_last_movement(product.id));
+        assert!(consume_for_order(vec![order_line(product.id, 2)], false).is_ok());
+        assert!(consume_for_order(vec![order_line(product.id, 1)], false).is_err());
+
+        set_time(time() + 86_400_000_000_000);
+        assert!(consume_for_order(vec![order_line(product.id, 5)], false).is_ok());
+    }
+
+    #[test]
+    fn undo_passes_over_archiving() {
+        add_controller(USER);
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 3);
+        ok(remove_product_from_warehouse(order_line(product.id, 3)));
+        assert_eq!(ok(archive_out_of_stock()), 1);
+
+        let undone = ok(undo_last_movement(product.id));
+
+        assert_eq!((undone.quantity, undone.total_dispensed), (3, 0));
+    }
+
+    #[test]
+    fn undo_leaves_transfers_alone() {
+        add_controller(USER);
+        let central = new_warehouse("Central");
+        let coast = new_warehouse("Coast");
+        let product = new_product("Paracetamol", central.id, 10);
+        let receipt = ok(transfer_product(product.id, coast.id, 4));
+
+        assert!(undo_last_movement(product.id).is_err());
+        assert!(undo_last_movement(receipt.target_product_id).is_err());
+        assert_eq!(ok(get_product_by_id(product.id)).quantity, 6);
+    }
+
+    #[test]
+    fn undo_checks_the_warehouse_has_room() {
+        add_controller(USER);
+        let warehouse = ok(add_warehouse(WarehousePayload {
+            capacity: 10,
+            ..warehouse_payload("Central")
+        }));
+        let product = new_product("Paracetamol", warehouse.id, 10);
+        ok(remove_product_from_warehouse(order_line(product.id, 5)));
+        new_product("Ibuprofen", warehouse.id, 5);
+
+        assert!(undo_last_movement(product.id).is_err());
+        assert_eq!(ok(get_product_by_id(product.id)).quantity, 5);
+    }
+
+    #[test]
+    fn fefo_skips_expired_lots() {
+        let warehouse = new_warehouse("Central");
+        let lot = |lot_number: &str, expires_at: u64| {
+            ok(add_product(ProductPayload {
+                lot_number: lot_number.to_string(),
+                expires_at,
+                ..product_payload("Paracetamol", warehouse.id, 5)
+            }))
+        };
+        let expired = lot("A1", 50);
+        let soon = lot("B1", 200);
+        let later = lot("C1", 300);
+        set_time(100);
+
+        let receipts = ok(dispense_fefo("Paracetamol".to_string(), warehouse.id, 7));
+
+        let taken: Vec<(u64, u32)> = receipts
+            .iter()
+            .map(|receipt| (receipt.product_id, receipt.removed))
+            .collect();
+        assert_eq!(taken, vec![(soon.id, 5), (later.id, 2)]);
+        assert_eq!(ok(get_product_by_id(expired.id)).quantity, 5);
+        assert!(dispense_fefo("Paracetamol".to_string(), warehouse.id, 4).is_err());
+    }
+
+    #[test]
+    fn movement_pages_dont_overlap() {
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+        for _ in 0..4 {
+            ok(add_product_to_warehouse(order_line(product.id, 1)));
+        }
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = get_movements_paginated(product.id, cursor, 2);
+            seen.extend(page.items.iter().map(|movement| movement.id));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        let all: Vec<u64> = get_product_movements(product.id)
+            .iter()
+            .map(|movement| movement.id)
+            .collect();
+        assert_eq!(seen, all);
+        assert_eq!(seen.len(), 5);
+    }
+
+    #[test]
+    fn archived_products_are_hidden_until_restocked() {
+        let warehouse = new_warehouse("Central");
+        let empty = new_product("Paracetamol", warehouse.id, 0);
+        new_product("Ibuprofen", warehouse.id, 10);
+        set_time(5);
+        assert_eq!(ok(archive_out_of_stock()), 1);
+        set_time(10);
+
+        assert_eq!(ok(get_all_products()).items.len(), 1);
+        assert!(search_products("paracetamol".to_string(), false).is_err());
+        assert!(get_products_by_quantity_range(0, 0).is_err());
+        assert_eq!(ok(get_never_restocked_products()).items.len(), 1);
+        assert_eq!(ok(get_products_missing_price()).items.len(), 1);
+        assert_eq!(ok(get_stale_products(0)).items.len(), 1);
+        assert_eq!(get_products_by_total_value(false, 100).len(), 1);
+        assert_eq!(get_top_dispensed_products(100).len(), 1);
+        assert!(get_recently_edited_products(100).is_empty());
+        assert!(ok(get_product_by_id(empty.id)).archived);
+
+        let restocked = ok(add_product_to_warehouse(order_line(empty.id, 5)));
+        assert!(!restocked.archived);
+        assert_eq!(ok(get_all_products()).items.len(), 2);
+    }
+
+    #[test]
+    fn snapshot_pages_restore_the_inventory() {
+        add_controller(USER);
+        for name in ["Central", "Coast", "Lake"] {
+            let warehouse = new_warehouse(name);
+            new_product("Paracetamol", warehouse.id, 10);
+        }
+        let first = ok(export_snapshot(0, 2));
+        let second = ok(export_snapshot(2, 2));
+        assert_eq!((first.product_total, first.warehouse_total), (3, 3));
+        assert_eq!((first.products.len(), second.products.len()), (2, 1));
+        let next_id = first.next_id;
+        new_warehouse("Highlands");
+
+        ok(import_snapshot(first, true));
+        ok(import_snapshot(second.clone(), false));
+
+        assert_eq!(get_product_ids().len(), 3);
+        assert_eq!(get_warehouse_ids().len(), 3);
+        assert_eq!(peek_next_id(), next_id);
+        // the ledger of the replaced records is dropped
+        assert_eq!(MOVEMENT_STORAGE.with(|s| s.borrow().len()), 0);
+        assert!(import_snapshot(second, false).is_err());
+    }
+
+    #[test]
+    fn rebalance_keeps_safety_stock() {
+        let central = new_warehouse("Central");
+        let coast = new_warehouse("Coast");
+        let reserved = ok(add_product(ProductPayload {
+            safety_stock: 8,
+            ..product_payload("Paracetamol", central.id, 10)
+        }));
+        new_product("Paracetamol", coast.id, 0);
+
+        let rebalance = || rebalance_product("Paracetamol".to_string(), "Medicine".to_string());
+        assert!(matches!(rebalance(), Err(Error::InvalidPayload { .. })));
+        assert_eq!(ok(get_product_by_id(reserved.id)).quantity, 10);
+
+        new_product("Ibuprofen", central.id, 10);
+        new_product("Ibuprofen", coast.id, 0);
+        let lots = ok(rebalance_product(
+            "Ibuprofen".to_string(),
+            "Medicine".to_string(),
+        ));
+        assert!(lots.iter().all(|lot| lot.quantity == 5));
+    }
+
+    #[test]
+    fn rebalance_keeps_lots_apart() {
+        let central = new_warehouse("Central");
+        let coast = new_warehouse("Coast");
+        let lot = |number: &str, warehouse_id: u64, quantity: u32, expires_at: u64| {
+            ok(add_product(ProductPayload {
+                lot_number: number.to_string(),
+                expires_at,
+                ..product_payload("Paracetamol", warehouse_id, quantity)
+            }))
+        };
+        let first = lot("A-1", central.id, 10, 0);
+        let second = lot("A-1", coast.id, 0, 0);
+        let other = lot("B-7", central.id, 6, 0);
+        let expired = lot("C-3", coast.id, 4, 100);
+        lot("C-3", central.id, 0, 100);
+        set_time(200);
+
+        let lots = ok(rebalance_product(
+            "Paracetamol".to_string(),
+            "Medicine".to_string(),
+        ));
+
+        let ids: Vec<u64> = lots.iter().map(|lot| lot.id).collect();
+        assert_eq!(ids, vec![first.id, second.id, other.id]);
+        assert_eq!(ok(get_product_by_id(first.id)).quantity, 5);
+        assert_eq!(ok(get_product_by_id(second.id)).quantity, 5);
+        // a lot held by one warehouse has nowhere to go
+        assert_eq!(ok(get_product_by_id(other.id)).version, other.version);
+        assert_eq!(ok(get_product_by_id(expired.id)).quantity, 4);
+    }
+
+    #[test]
+    fn warehouse_listings_are_capped() {
+        let list = warehouse_list(vec![Warehouse::default(); MAX_RESULTS + 1]);
+        assert_eq!(list.items.len(), MAX_RESULTS);
+        assert!(list.truncated);
+
+        new_warehouse("Central");
+        let open = get_open_warehouses(0, 600);
+        assert_eq!(open.items.len(), 1);
+        assert!(!open.truncated);
+    }
+
+    #[test]
+    fn replayed_delivery_is_not_applied_twice() {
+        let central = new_warehouse("Central");
+        let product = new_product("Paracetamol", central.id, 10);
+        let line = || GetProductPayload {
+            product_id: product.id,
+            amount: 5,
+            override_safety_stock: false,
+        };
+
+        ok(record_delivery("DN-1001".to_string(), vec![line()]));
+        let replayed = ok(record_delivery(
+            " DN-1001 ".to_string(),
+            vec![line(), line()],
+        ));
+
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(ok(get_product_by_id(product.id)).quantity, 15);
+        assert!(record_delivery("x".repeat(MAX_DELIVERY_ID_BYTES + 1), vec![line()]).is_err());
+    }
+
+    #[test]
+    fn category_reorder_defaults_match_product_categories() {
+        add_controller(USER);
+        let too_long = "x".repeat(MAX_LABEL_BYTES + 1);
+        assert!(set_category_reorder_default(too_long, 5).is_err());
+
+        ok(set_category_reorder_default("  medicine ".to_string(), 5));
+        ok(set_category_reorder_default("MEDICINE".to_string(), 12));
+        assert_eq!(get_category_reorder_defaults().len(), 1);
+
+        let warehouse = new_warehouse("Central");
+        let product = new_product("Paracetamol", warehouse.id, 10);
+        assert_eq!(product.reorder_level, 12);
+    }
+
+    #[test]
+    fn opening_hours_stay_within_a_week() {
+        let last = MINUTES_PER_DAY - 1;
+        assert!(validate_opening_hours(&[(LAST_WEEKDAY, 0, last)]).is_ok());
+        assert!(validate_opening_hours(&[(LAST_WEEKDAY + 1, 0, last)]).is_err());
+        assert!(validate_opening_hours(&[(0, 0, MINUTES_PER_DAY)]).is_err());
+        assert!(validate_opening_hours(&[(0, 600, 600)]).is_err());
+
+        let message = validate_opening_hours(&[(0, 0, MINUTES_PER_DAY)])
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains(&format!("open < close < {}", MINUTES_PER_DAY)));
+    }
+
+    #[test]
+    fn reconcile_up_is_checked_like_a_restock() {
+        let warehouse = ok(add_warehouse(WarehousePayload {
+            capacity: 20,
+            ..warehouse_payload("Central")
+        }));
+        let capped = ok(add_product(ProductPayload {
+            max_stock: 15,
+            ..product_payload("Paracetamol", warehouse.id, 10)
+        }));
+        let other = new_product("Ibuprofen", warehouse.id, 5);
+        let reconcile = |id: u64, counted: u32| reconcile_product(id, counted, "count".to_string());
+
+        assert!(reconcile(capped.id, 16).is_err());
+        assert_eq!(ok(reconcile(capped.id, 15)).quantity, 15);
+        assert!(reconcile(other.id, 6).is_err());
+        assert_eq!(ok(reconcile(capped.id, 3)).quantity, 3);
+    }
+}
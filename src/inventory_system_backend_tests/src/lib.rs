@@ -0,0 +1,5 @@
+// The tests of this crate live in tests/ and install the backend wasm in PocketIC.
+// Build the wasm with
+//     cargo build --target wasm32-unknown-unknown --release -p inventory_system_backend
+// and point POCKET_IC_BIN at a PocketIC server binary, then run
+//     cargo test --manifest-path src/inventory_system_backend_tests/Cargo.toml -- --ignored
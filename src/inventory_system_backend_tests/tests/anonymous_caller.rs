@@ -0,0 +1,117 @@
+use candid::{encode_one, CandidType, Deserialize, Principal, Reserved};
+use pocket_ic::common::rest::RawEffectivePrincipal;
+use pocket_ic::{call_candid_as, PocketIc};
+
+// Error of the backend, only the variant is looked at
+#[derive(CandidType, Deserialize, Debug)]
+enum Error {
+    NotFound { msg: String },
+    NotFoundWithSuggestions { msg: String, nearest: Vec<u64> },
+    AlreadyInit { msg: String },
+    InvalidPayload { msg: String },
+    Unauthorized { msg: String },
+    VersionConflict { expected: u64, actual: u64 },
+}
+
+#[derive(CandidType, Default)]
+struct ProductPayload {
+    name: String,
+    category: String,
+    quantity: u32,
+    unit_of_measure: String,
+    unit_price: u64,
+    cost_price: u64,
+    image_url: Option<String>,
+    alert_threshold: u32,
+    max_stock: u32,
+    safety_stock: u32,
+    reorder_level: Option<u32>,
+    lot_number: String,
+    expires_at: u64,
+    weight_grams: u32,
+    volume_cm3: u32,
+    warehouse_id: u64,
+}
+
+// wasm built with cargo build --target wasm32-unknown-unknown --release,
+// BACKEND_WASM overrides where it is read from
+fn backend_wasm() -> Vec<u8> {
+    let path = std::env::var("BACKEND_WASM").unwrap_or_else(|_| {
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../target/wasm32-unknown-unknown/release/inventory_system_backend.wasm"
+        )
+        .to_string()
+    });
+    std::fs::read(&path).unwrap_or_else(|err| panic!("could not read {}: {}", path, err))
+}
+
+// install the backend with the default config
+fn setup() -> (PocketIc, Principal) {
+    let pic = PocketIc::new();
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000);
+    let config: Option<Reserved> = None;
+    pic.install_canister(
+        canister_id,
+        backend_wasm(),
+        encode_one(config).unwrap(),
+        None,
+    );
+    (pic, canister_id)
+}
+
+fn add_product(
+    pic: &PocketIc,
+    canister_id: Principal,
+    sender: Principal,
+) -> Result<Reserved, Error> {
+    let payload = ProductPayload {
+        name: "Widget".to_string(),
+        category: "tools".to_string(),
+        quantity: 1,
+        unit_of_measure: "each".to_string(),
+        warehouse_id: 999,
+        ..Default::default()
+    };
+    let (result,): (Result<Reserved, Error>,) = call_candid_as(
+        pic,
+        canister_id,
+        RawEffectivePrincipal::None,
+        sender,
+        "add_product",
+        (payload,),
+    )
+    .expect("add_product call failed");
+    result
+}
+
+#[test]
+#[ignore = "needs a PocketIC server binary in POCKET_IC_BIN and a release wasm build"]
+fn anonymous_add_product_is_rejected() {
+    let (pic, canister_id) = setup();
+
+    let result = add_product(&pic, canister_id, Principal::anonymous());
+
+    assert!(
+        matches!(result, Err(Error::Unauthorized { .. })),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+#[ignore = "needs a PocketIC server binary in POCKET_IC_BIN and a release wasm build"]
+fn authenticated_add_product_gets_past_the_guard() {
+    let (pic, canister_id) = setup();
+    let caller = Principal::from_slice(&[1; 29]);
+
+    // the warehouse doesn't exist, so the call fails after the caller is accepted
+    let result = add_product(&pic, canister_id, caller);
+
+    assert!(
+        matches!(result, Err(Error::NotFound { .. })),
+        "{:?}",
+        result
+    );
+}